@@ -1,10 +1,10 @@
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::entrypoint::ProgramResult;
-use anchor_lang::solana_program::program::invoke;
+use anchor_lang::solana_program::program::{invoke, invoke_signed};
+use anchor_lang::solana_program::program_option::COption;
 use anchor_spl::token::{MintTo, Token};
-use mpl_token_metadata;
 use std::mem::size_of;
-use mpl_token_metadata::state::{Creator};
+use mpl_token_metadata::state::{Creator, Metadata, TokenMetadataAccount};
 
 declare_id!("C7KQdF6atRDnJe9cCLomcESLFZCtYa9SEpRT5i9Y4J3u");
 
@@ -23,201 +23,525 @@ mod upgrade_weapon {
         upgrade_weapon.symbol = symbol;
         upgrade_weapon.token_types = vec![];
         upgrade_weapon.token_type_uris = vec![];
+        upgrade_weapon.live_supply = [0, 0, 0, 0, 0];
+        upgrade_weapon.live_supply_migrated = true;
+        upgrade_weapon.collection_mint = Pubkey::default();
 
         Ok(())
     }
 
-    pub fn mint(ctx: Context<MintToken>, token_type: u8) -> ProgramResult {
+    pub fn mint(
+        ctx: Context<MintToken>,
+        token_type: u8,
+        device: TokenDevice,
+        phase: u8,
+        proof: Vec<[u8; 32]>,
+        rarity_seed: [u8; 32],
+    ) -> ProgramResult {
         let upgrade_weapon = &mut ctx.accounts.upgrade_weapon;
-        let nft_account = &mut ctx.accounts.nft_account;
-    
-        if nft_account.token_type[token_type as usize] == 1 {
-            return Err(ErrorCode::DuplicatedDevice.into());
+
+        upgrade_weapon.check_token_type(token_type)?;
+
+        if device.token_type != token_type {
+            return Err(ErrorCode::InvalidTokenType.into());
         }
-        nft_account.token_type[token_type as usize] = 1;
-    
-        anchor_lang::system_program::create_account(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                system_program::CreateAccount {
-                    from: ctx.accounts.authority.to_account_info(),
-                    to: ctx.accounts.mint.to_account_info(),
-                },
-            ),
-            10000000,
-            82,
-            &ctx.accounts.token_program.key(),
+
+        let now = Clock::get()?.unix_timestamp;
+
+        // Free types can only be claimed once per device, whichever wallet asks.
+        // Paid types don't need the backend's attestation at all.
+        if token_type == 0 || token_type == 4 {
+            if now > device.expires_at {
+                return Err(ErrorCode::SignatureExpired.into());
+            }
+
+            crate::verify_ed25519_instruction(
+                &ctx.accounts.instructions,
+                &solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"),
+                &device.attestation_message(&ctx.accounts.authority.key()),
+            )?;
+
+            // Only free mints create the device's account, so paid ones can't
+            // be made to pay for accounts under arbitrary device ids.
+            let device_info = ctx.accounts.device_account.to_account_info();
+            let mut device_account = DeviceAccount::load_or_create(
+                &device_info,
+                &ctx.accounts.authority.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+                &device.hash(),
+            )?;
+
+            if device_account.token_type[token_type as usize] == 1 {
+                return Err(ErrorCode::DuplicatedDevice.into());
+            }
+            device_account.token_type[token_type as usize] = 1;
+            device_account.store(&device_info)?;
+        }
+
+        let config = &TokenTypeConfig::load_or_default(
+            &ctx.accounts.token_type_config,
+            &upgrade_weapon.key(),
+            token_type,
         )?;
 
-        anchor_spl::token::initialize_mint(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                anchor_spl::token::InitializeMint {
-                    mint: ctx.accounts.mint.to_account_info(),
-                    rent: ctx.accounts.rent.to_account_info(),
-                },
-            ),
-            0,
+        let legacy_minted = MintedNFTAccount::legacy_minted(&ctx.accounts.nft_account, token_type)?;
+        config.record_mint(&mut ctx.accounts.mint_counter, legacy_minted, now)?;
+
+        // Types without phases never need the per-phase counter, so it's only
+        // created once the admin sets some.
+        let phase_counter_info = ctx.accounts.phase_counter.to_account_info();
+        let mut phase_counter = if config.phases.is_empty() {
+            None
+        } else {
+            Some(MintCounter::load_or_create(
+                &phase_counter_info,
+                &ctx.accounts.authority.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+                &[
+                    b"mintCounter",
+                    upgrade_weapon.key().as_ref(),
+                    ctx.accounts.authority.key().as_ref(),
+                    &[token_type],
+                    &[phase],
+                ],
+            )?)
+        };
+
+        let price = config.record_phase_mint(
+            phase,
             &ctx.accounts.authority.key(),
-            Some(&ctx.accounts.authority.key()),
+            &proof,
+            phase_counter.as_mut(),
+            now,
         )?;
 
-        anchor_spl::associated_token::create(CpiContext::new(
-            ctx.accounts.token_account.to_account_info(),
-            anchor_spl::associated_token::Create {
-                payer: ctx.accounts.authority.to_account_info(),
-                associated_token: ctx.accounts.token_account.to_account_info(),
-                authority: ctx.accounts.authority.to_account_info(),
-                mint: ctx.accounts.mint.to_account_info(),
-                system_program: ctx.accounts.system_program.to_account_info(),
-                token_program: ctx.accounts.token_program.to_account_info(),
-            },
-        ))?;
+        if let Some(phase_counter) = &phase_counter {
+            phase_counter.store(&phase_counter_info)?;
+        }
 
-        // Create the MintTo struct for our context
-        let cpi_accounts = MintTo {
-            mint: ctx.accounts.mint.to_account_info(),
-            to: ctx.accounts.token_account.to_account_info(),
+        upgrade_weapon.check_collection(&ctx.accounts.collection_mint.key())?;
+
+        let rarity = config.roll_signed_rarity(&ctx.accounts.instructions, &ctx.accounts.mint.key(), &rarity_seed)?;
+        let (name, uri) = upgrade_weapon.record_mint(token_type, &ctx.accounts.weapon_account.key(), rarity)?;
+
+        // Every check is done: only now do we take payment and touch other programs.
+        let pipeline = MintPipeline {
             authority: ctx.accounts.authority.to_account_info(),
+            recipient: ctx.accounts.authority.to_account_info(),
+            owner: ctx.accounts.owner.to_account_info(),
+            collection: ctx.accounts.collection.to_account_info(),
+            collection_mint: ctx.accounts.collection_mint.to_account_info(),
+            collection_master_edition: ctx.accounts.collection_master_edition.to_account_info(),
+            rent: ctx.accounts.rent.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+        };
+
+        let item = MintItem {
+            mint: ctx.accounts.mint.to_account_info(),
+            token_account: ctx.accounts.token_account.to_account_info(),
+            metadata_account: ctx.accounts.metadata_account.to_account_info(),
+            edition: ctx.accounts.edition.to_account_info(),
+            weapon_account: ctx.accounts.weapon_account.to_account_info(),
         };
 
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        pipeline.pay(price)?;
+        pipeline.create_token(&item)?;
+        pipeline.create_metadata(&item, name, upgrade_weapon.symbol.clone(), uri)?;
 
-        let token_id: u64 = upgrade_weapon.token_type_counter;
-        let u64_token_type: u64 = token_type.into();
+        if config.soulbound {
+            pipeline.bind(&item, &ctx.accounts.freeze_authority)?;
+        }
 
-        upgrade_weapon.token_types.push(TokenType {
-            id: token_id,
-            token_type: u64_token_type,
+        ctx.accounts.weapon_account.set_inner(Weapon::new(
+            ctx.accounts.mint.key(),
+            ctx.accounts.authority.key(),
+            rarity.unwrap_or_default(),
+        ));
+        ctx.accounts.mint_record.set_inner(MintRecord {
+            token_type,
+            source: MintSource::Mint,
+            minter: ctx.accounts.authority.key(),
+            price,
+            minted_at: now,
         });
 
+        Ok(())
+    }
+
+    /// Mints several paid weapons in one go, e.g. a starter pack. For every entry
+    /// of `token_types`, `remaining_accounts` holds the `MINT_BATCH_ACCOUNTS`
+    /// accounts listed on `MintBatch`, and each item goes through the same limits,
+    /// pricing, rarity roll and collection verification as `mint`.
+    pub fn mint_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, MintBatch<'info>>,
+        token_types: Vec<u8>,
+        phase: u8,
+        proofs: Vec<Vec<[u8; 32]>>,
+        rarity_seeds: Vec<[u8; 32]>,
+    ) -> ProgramResult {
+        if token_types.len() > MAX_MINT_BATCH {
+            msg!(&format!(
+                "A batch can mint at most {} weapons: each needs close to 400k compute units and a transaction gets 1.4M",
+                MAX_MINT_BATCH
+            ));
+            return Err(ErrorCode::BatchTooLarge.into());
+        }
 
-        // Execute anchor's helper function to mint tokens
-        anchor_spl::token::mint_to(cpi_ctx, 1)?;
+        if ctx.remaining_accounts.len() != token_types.len() * MINT_BATCH_ACCOUNTS
+            || proofs.len() != token_types.len()
+            || rarity_seeds.len() != token_types.len()
+        {
+            return Err(ErrorCode::InvalidBatchAccounts.into());
+        }
 
-        let index = upgrade_weapon
-            .token_type_uris
+        let now = Clock::get()?.unix_timestamp;
+        let wallet = ctx.accounts.authority.key();
+        let upgrade_weapon_key = ctx.accounts.upgrade_weapon.key();
+        let upgrade_weapon = &mut ctx.accounts.upgrade_weapon;
+
+        upgrade_weapon.check_collection(&ctx.accounts.collection_mint.key())?;
+
+        let pipeline = MintPipeline {
+            authority: ctx.accounts.authority.to_account_info(),
+            recipient: ctx.accounts.authority.to_account_info(),
+            owner: ctx.accounts.owner.to_account_info(),
+            collection: ctx.accounts.collection.to_account_info(),
+            collection_mint: ctx.accounts.collection_mint.to_account_info(),
+            collection_master_edition: ctx.accounts.collection_master_edition.to_account_info(),
+            rent: ctx.accounts.rent.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+        };
+
+        let items = token_types
             .iter()
-            .position(|t| t.id == u64_token_type)
-            .unwrap();
+            .zip(&proofs)
+            .zip(&rarity_seeds)
+            .zip(ctx.remaining_accounts.chunks(MINT_BATCH_ACCOUNTS));
 
-        let token_type_uri = upgrade_weapon.token_type_uris[index].clone();
+        // Check and count every item before paying for or creating any of them.
+        let mut checked = Vec::with_capacity(token_types.len());
 
-        if token_type != 4 && token_type != 0 {
-            if upgrade_weapon.token_counter[token_type as usize] >= 200 {
-                return Err(ErrorCode::NftLimitExceeded.into());
+        for (((&token_type, proof), rarity_seed), accounts) in items {
+            upgrade_weapon.check_token_type(token_type)?;
+
+            // Free types stay one per device, which only `mint` can check.
+            if token_type == 0 || token_type == 4 {
+                return Err(ErrorCode::FreeMintNotBatchable.into());
+            }
+
+            let item = MintItem {
+                mint: accounts[0].clone(),
+                token_account: accounts[1].clone(),
+                metadata_account: accounts[2].clone(),
+                edition: accounts[3].clone(),
+                weapon_account: accounts[4].clone(),
+            };
+
+            let config = TokenTypeConfig::load_or_default(&accounts[5], &upgrade_weapon_key, token_type)?;
+
+            let mut mint_counter = MintCounter::load_or_create(
+                &accounts[6],
+                &pipeline.authority,
+                &pipeline.system_program,
+                &[b"mintCounter", upgrade_weapon_key.as_ref(), wallet.as_ref(), &[token_type]],
+            )?;
+            let mut phase_counter = if config.phases.is_empty() {
+                None
+            } else {
+                Some(MintCounter::load_or_create(
+                    &accounts[7],
+                    &pipeline.authority,
+                    &pipeline.system_program,
+                    &[b"mintCounter", upgrade_weapon_key.as_ref(), wallet.as_ref(), &[token_type], &[phase]],
+                )?)
+            };
+
+            let legacy_minted = MintedNFTAccount::legacy_minted(&ctx.accounts.nft_account, token_type)?;
+            config.record_mint(&mut mint_counter, legacy_minted, now)?;
+            let price = config.record_phase_mint(phase, &wallet, proof, phase_counter.as_mut(), now)?;
+
+            mint_counter.store(&accounts[6])?;
+            if let Some(phase_counter) = &phase_counter {
+                phase_counter.store(&accounts[7])?;
             }
+
+            let rarity = config.roll_signed_rarity(&ctx.accounts.instructions, item.mint.key, rarity_seed)?;
+            let (name, uri) = upgrade_weapon.record_mint(token_type, &item.weapon_account.key(), rarity)?;
+
+            checked.push((token_type, item, &accounts[8], name, uri, price, config.soulbound, rarity));
         }
 
-        upgrade_weapon.token_counter[token_type as usize] += 1;
-        
+        for (token_type, item, mint_record, name, uri, price, soulbound, rarity) in checked {
+            pipeline.pay(price)?;
+            pipeline.create_token(&item)?;
+            pipeline.create_metadata(&item, name, upgrade_weapon.symbol.clone(), uri)?;
 
-        let cpi_context = CpiContext::new(
-            ctx.accounts.system_program.to_account_info(), 
-            system_program::Transfer {
-                from: ctx.accounts.authority.to_account_info(),
-                to: ctx.accounts.owner.to_account_info(),
-            });
+            if soulbound {
+                pipeline.bind(&item, &ctx.accounts.freeze_authority)?;
+            }
+
+            crate::create_pda_account(
+                &item.weapon_account,
+                &pipeline.authority,
+                &pipeline.system_program,
+                &[b"weapon", item.mint.key.as_ref()],
+                8 + Weapon::INIT_SPACE,
+            )?;
+            Weapon::new(item.mint.key(), wallet, rarity.unwrap_or_default())
+                .try_serialize(&mut &mut item.weapon_account.try_borrow_mut_data()?[..])?;
 
-        if token_type != 0 && token_type != 4 {
-            system_program::transfer(cpi_context, 100000000)?;
+            crate::create_pda_account(
+                mint_record,
+                &pipeline.authority,
+                &pipeline.system_program,
+                &[b"mintRecord", item.mint.key.as_ref()],
+                8 + MintRecord::INIT_SPACE,
+            )?;
+            MintRecord {
+                token_type,
+                source: MintSource::Batch,
+                minter: wallet,
+                price,
+                minted_at: now,
+            }
+            .try_serialize(&mut &mut mint_record.try_borrow_mut_data()?[..])?;
         }
 
-        let creators = vec![
-            Creator {
-                address: ctx.accounts.owner.key(),
-                verified: true,
-                share: 100,
-            },
-            // Add more creators if needed
-        ];
+        Ok(())
+    }
 
-        msg!(&ctx.accounts.owner.key().to_string());
+    /// Mints a weapon of `token_type` straight to `recipient`, e.g. for tournament
+    /// rewards or support compensation. Nothing is charged and per-wallet limits
+    /// don't apply, but the weapon still counts against the type's supply.
+    /// `recipient` doesn't sign, so soulbound weapons couldn't be frozen here:
+    /// those types are refused, hand them out with a voucher instead.
+    pub fn airdrop(ctx: Context<Airdrop>, token_type: u8, rarity_seed: [u8; 32]) -> ProgramResult {
+        let upgrade_weapon = &mut ctx.accounts.upgrade_weapon;
 
-        invoke(
-            &mpl_token_metadata::instruction::create_metadata_accounts_v3(
-                mpl_token_metadata::ID,
-                ctx.accounts.metadata_account.key(),
-                ctx.accounts.mint.key(),
-                ctx.accounts.authority.key(),
-                ctx.accounts.authority.key(),
-                ctx.accounts.owner.key(),
-                token_type_uri.name.clone(),
-                upgrade_weapon.symbol.clone(),
-                token_type_uri.token_uri + "/2/" + &ctx.accounts.weapon_account.key().to_string() + "/" + &upgrade_weapon.token_counter[token_type as usize].to_string(),
-                Some(creators),
-                500,
-                true,
-                false,
-                None,
-                None,
-                None,
-            ),
-            &[
-                ctx.accounts.metadata_account.to_account_info(),
-                ctx.accounts.mint.to_account_info(),
-                ctx.accounts.token_account.to_account_info(),
-                ctx.accounts.authority.to_account_info(),
-                ctx.accounts.rent.to_account_info(),
-                ctx.accounts.owner.to_account_info(),
-            ],
-        )?;
+        upgrade_weapon.check_token_type(token_type)?;
 
-        msg!("Token mint process completed successfully.");
+        if ctx.accounts.token_type_config.soulbound {
+            return Err(ErrorCode::Soulbound.into());
+        }
+        upgrade_weapon.check_collection(&ctx.accounts.collection_mint.key())?;
 
-        invoke(
-            &mpl_token_metadata::instruction::create_master_edition_v3(
-                mpl_token_metadata::ID,
-                ctx.accounts.edition.key(),
-                ctx.accounts.mint.key(),
-                ctx.accounts.owner.key(),
-                ctx.accounts.authority.key(),
-                ctx.accounts.metadata_account.key(),
-                ctx.accounts.authority.key(),
-                None,
+        // The admin signing this transaction is the backend, so its seed needs
+        // no separate signature.
+        let rarity = ctx
+            .accounts
+            .token_type_config
+            .roll_rarity(&crate::rarity_seed(&rarity_seed, &ctx.accounts.mint.key()));
+        let (name, uri) = upgrade_weapon.record_mint(token_type, &ctx.accounts.weapon_account.key(), rarity)?;
+
+        let pipeline = MintPipeline {
+            authority: ctx.accounts.authority.to_account_info(),
+            recipient: ctx.accounts.recipient.to_account_info(),
+            owner: ctx.accounts.authority.to_account_info(),
+            collection: ctx.accounts.collection.to_account_info(),
+            collection_mint: ctx.accounts.collection_mint.to_account_info(),
+            collection_master_edition: ctx.accounts.collection_master_edition.to_account_info(),
+            rent: ctx.accounts.rent.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+        };
+
+        let item = MintItem {
+            mint: ctx.accounts.mint.to_account_info(),
+            token_account: ctx.accounts.token_account.to_account_info(),
+            metadata_account: ctx.accounts.metadata_account.to_account_info(),
+            edition: ctx.accounts.edition.to_account_info(),
+            weapon_account: ctx.accounts.weapon_account.to_account_info(),
+        };
+
+        pipeline.create_token(&item)?;
+        pipeline.create_metadata(&item, name, upgrade_weapon.symbol.clone(), uri)?;
+
+        ctx.accounts.weapon_account.set_inner(Weapon::new(
+            ctx.accounts.mint.key(),
+            ctx.accounts.recipient.key(),
+            rarity.unwrap_or_default(),
+        ));
+        ctx.accounts.mint_record.set_inner(MintRecord {
+            token_type,
+            source: MintSource::Airdrop,
+            minter: ctx.accounts.recipient.key(),
+            price: 0,
+            minted_at: Clock::get()?.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    /// Pays a minter back what they paid for a weapon, e.g. when its type was
+    /// pulled after the sale. The record's price drops to zero so it can't be
+    /// refunded twice; the weapon itself is left alone.
+    pub fn refund(ctx: Context<Refund>) -> ProgramResult {
+        let mint_record = &mut ctx.accounts.mint_record;
+        let amount = mint_record.price;
+
+        if amount == 0 {
+            return Err(ErrorCode::NothingToRefund.into());
+        }
+
+        mint_record.price = 0;
+
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.authority.to_account_info(),
+                    to: ctx.accounts.minter.to_account_info(),
+                },
             ),
-            &[
-                ctx.accounts.edition.to_account_info(),
-                ctx.accounts.metadata_account.to_account_info(),
-                ctx.accounts.mint.to_account_info(),
-                ctx.accounts.token_account.to_account_info(),
-                ctx.accounts.authority.to_account_info(),
-                ctx.accounts.rent.to_account_info(),
-                ctx.accounts.owner.to_account_info(),
-            ],
+            amount,
         )?;
 
-        invoke(
-            &mpl_token_metadata::instruction::set_and_verify_sized_collection_item(
-                mpl_token_metadata::ID,
-                ctx.accounts.metadata_account.key(),
-                ctx.accounts.owner.key(),
-                ctx.accounts.authority.key(),
-                ctx.accounts.owner.key(),
-                ctx.accounts.collection_mint.key(),
-                ctx.accounts.collection.key(),
-                ctx.accounts.collection_master_edition.key(),
-                None,
-            ),
-            &[
-                ctx.accounts.owner.to_account_info(),
-                ctx.accounts.authority.to_account_info(),
-                ctx.accounts.metadata_account.to_account_info(),
-                ctx.accounts.collection_mint.to_account_info(),
-                ctx.accounts.collection.to_account_info(),
-                ctx.accounts.collection_master_edition.to_account_info(),
-            ],
+        emit!(MintRefunded {
+            mint: ctx.accounts.mint.key(),
+            minter: ctx.accounts.minter.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    /// Thaws a soulbound weapon's token account for support cases, e.g. moving
+    /// it off a compromised wallet. `transfer` still refuses soulbound types.
+    /// Only soulbound types are thawed: anything else the `freezeAuthority`
+    /// froze is locked for a reason of its own.
+    pub fn unbind(ctx: Context<Unbind>) -> ProgramResult {
+        // Staked and equipped soulbound weapons stay frozen by the stake or the
+        // profile, and the type may have turned soulbound while the weapon was
+        // lent out.
+        if ctx.accounts.weapon_account.equipped_to != Pubkey::default()
+            || !ctx.accounts.stake_account.data_is_empty()
+            || !ctx.accounts.rental.data_is_empty()
+            || !ctx.accounts.token_type_config.soulbound
+        {
+            return Err(ErrorCode::WeaponLocked.into());
+        }
+
+        crate::set_weapon_frozen(
+            false,
+            &ctx.accounts.freeze_authority,
+            &ctx.accounts.token_account.to_account_info(),
+            &ctx.accounts.edition,
+            &ctx.accounts.mint.to_account_info(),
+            &ctx.accounts.token_program,
+        )
+    }
+
+    pub fn create_voucher(
+        ctx: Context<CreateVoucher>,
+        _id: u64,
+        token_type: u8,
+        recipient: Option<Pubkey>,
+        expires_at: Option<i64>,
+        uses: u32,
+    ) -> ProgramResult {
+        ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
+
+        // Each wallet redeems a voucher once, so a voucher bound to one
+        // recipient could never use up, nor close, more than a single use.
+        if uses == 0 || (recipient.is_some() && uses > 1) {
+            return Err(ErrorCode::InvalidVoucher.into());
+        }
+
+        ctx.accounts.voucher.set_inner(Voucher {
+            token_type,
+            recipient,
+            expires_at,
+            uses,
+        });
+
+        Ok(())
+    }
+
+    /// Mints the weapon a voucher is good for to the redeeming player, who pays
+    /// the fees. Each wallet can redeem a given voucher once, and the voucher is
+    /// closed once its last use is gone. The admin `owner` still co-signs: it is
+    /// the weapon's verified creator and the collection authority, and Token
+    /// Metadata only accepts both with its signature. The backend adds it after
+    /// checking the player's transaction, like for `mint`.
+    pub fn redeem_voucher(ctx: Context<RedeemVoucher>, _id: u64, rarity_seed: [u8; 32]) -> ProgramResult {
+        let now = Clock::get()?.unix_timestamp;
+        let voucher = &mut ctx.accounts.voucher;
+
+        if matches!(voucher.expires_at, Some(expires_at) if now >= expires_at) {
+            return Err(ErrorCode::VoucherExpired.into());
+        }
+
+        if matches!(voucher.recipient, Some(recipient) if recipient != ctx.accounts.authority.key()) {
+            return Err(ErrorCode::InvalidTokenOwner.into());
+        }
+
+        voucher.uses -= 1;
+        let token_type = voucher.token_type;
+
+        let upgrade_weapon = &mut ctx.accounts.upgrade_weapon;
+        upgrade_weapon.check_collection(&ctx.accounts.collection_mint.key())?;
+
+        let rarity = ctx.accounts.token_type_config.roll_signed_rarity(
+            &ctx.accounts.instructions,
+            &ctx.accounts.mint.key(),
+            &rarity_seed,
         )?;
+        let (name, uri) = upgrade_weapon.record_mint(token_type, &ctx.accounts.weapon_account.key(), rarity)?;
 
-        let metadata_acc: &mut Account<'_, Weapon> = &mut ctx.accounts.weapon_account;
+        let pipeline = MintPipeline {
+            authority: ctx.accounts.authority.to_account_info(),
+            recipient: ctx.accounts.authority.to_account_info(),
+            owner: ctx.accounts.owner.to_account_info(),
+            collection: ctx.accounts.collection.to_account_info(),
+            collection_mint: ctx.accounts.collection_mint.to_account_info(),
+            collection_master_edition: ctx.accounts.collection_master_edition.to_account_info(),
+            rent: ctx.accounts.rent.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+        };
+
+        let item = MintItem {
+            mint: ctx.accounts.mint.to_account_info(),
+            token_account: ctx.accounts.token_account.to_account_info(),
+            metadata_account: ctx.accounts.metadata_account.to_account_info(),
+            edition: ctx.accounts.edition.to_account_info(),
+            weapon_account: ctx.accounts.weapon_account.to_account_info(),
+        };
+
+        pipeline.create_token(&item)?;
+        pipeline.create_metadata(&item, name, upgrade_weapon.symbol.clone(), uri)?;
+
+        if ctx.accounts.token_type_config.soulbound {
+            pipeline.bind(&item, &ctx.accounts.freeze_authority)?;
+        }
+
+        ctx.accounts.weapon_account.set_inner(Weapon::new(
+            ctx.accounts.mint.key(),
+            ctx.accounts.authority.key(),
+            rarity.unwrap_or_default(),
+        ));
+        ctx.accounts.mint_record.set_inner(MintRecord {
+            token_type,
+            source: MintSource::Voucher,
+            minter: ctx.accounts.authority.key(),
+            price: 0,
+            minted_at: now,
+        });
+        ctx.accounts.redemption.redeemed_at = now;
+
+        if ctx.accounts.voucher.uses == 0 {
+            ctx.accounts.voucher.close(ctx.accounts.owner.to_account_info())?;
+        }
+
+        Ok(())
+    }
 
-        metadata_acc.level = 0;
-        metadata_acc.hp = 0;
-        metadata_acc.damage = 0;
-        metadata_acc.mana = 0;
-        metadata_acc.mp_regen = 0;
-        metadata_acc.atk_speed = 0;
+    /// Sets the collection weapons are verified into. Once set, mints must use
+    /// it and only its members can be burned.
+    pub fn set_collection(ctx: Context<SetCollection>, collection_mint: Pubkey) -> ProgramResult {
+        ctx.accounts.upgrade_weapon.collection_mint = collection_mint;
 
         Ok(())
     }
@@ -269,376 +593,4772 @@ mod upgrade_weapon {
         Ok(())
     }
 
-    pub fn set_token_type_uri(
-        ctx: Context<SetTokenTypeURI>,
-        token_type: u64,
-        token_uri: String,
+    pub fn set_mint_limits(
+        ctx: Context<SetTokenTypeConfig>,
+        token_type: u8,
+        wallet_limit: u32,
+        window_limit: u32,
+        window_seconds: i64,
     ) -> ProgramResult {
-        let upgrade_weapon = &mut ctx.accounts.upgrade_weapon;
+        ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
 
-        if token_type >= upgrade_weapon.token_type_counter {
-            return Err(ErrorCode::InvalidTokenType.into());
+        if window_limit != 0 && window_seconds <= 0 {
+            return Err(ErrorCode::InvalidMintLimit.into());
         }
 
-        let index = upgrade_weapon
-            .token_type_uris
-            .iter()
-            .position(|t| t.id == token_type)
-            .unwrap();
-        upgrade_weapon.token_type_uris[index].token_uri = token_uri;
+        let config = &mut ctx.accounts.token_type_config;
+
+        config.token_type = token_type;
+        config.wallet_limit = wallet_limit;
+        config.window_limit = window_limit;
+        config.window_seconds = window_seconds;
 
         Ok(())
     }
 
-    pub fn token_uri(ctx: Context<TokenURI>, token_id: u64) -> ProgramResult {
-        let upgrade_weapon = &ctx.accounts.upgrade_weapon;
+    pub fn set_mint_phases(
+        ctx: Context<SetTokenTypeConfig>,
+        token_type: u8,
+        phases: Vec<MintPhase>,
+    ) -> ProgramResult {
+        ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
 
-        if !upgrade_weapon.token_types.iter().any(|t| t.id == token_id) {
-            return Err(ErrorCode::InvalidTokenId.into());
+        if phases.len() > MAX_MINT_PHASES || phases.iter().any(|p| p.start >= p.end) {
+            return Err(ErrorCode::InvalidMintPhase.into());
         }
 
-        let token_type = upgrade_weapon
-            .token_types
-            .iter()
-            .find(|t| t.id == token_id)
-            .unwrap()
-            .token_type;
+        let config = &mut ctx.accounts.token_type_config;
 
-        let token_type_uri = upgrade_weapon
-            .token_type_uris
-            .iter()
-            .find(|t| t.id == token_type)
-            .unwrap()
-            .token_uri
-            .clone();
+        config.token_type = token_type;
+        config.phases = phases;
 
-        let token_account = &ctx.accounts.token_account;
-        if token_account.owner != ctx.accounts.user.key() {
-            return Err(ErrorCode::InvalidTokenOwner.into());
+        Ok(())
+    }
+
+    pub fn set_mint_window(
+        ctx: Context<SetTokenTypeConfig>,
+        token_type: u8,
+        mint_start: Option<i64>,
+        mint_end: Option<i64>,
+    ) -> ProgramResult {
+        ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
+
+        if let (Some(start), Some(end)) = (mint_start, mint_end) {
+            if start >= end {
+                return Err(ErrorCode::InvalidMintWindow.into());
+            }
         }
 
-        emit!(TokenURISuccess {
-            token_uri: token_type_uri
-        });
+        let config = &mut ctx.accounts.token_type_config;
+
+        config.token_type = token_type;
+        config.mint_start = mint_start;
+        config.mint_end = mint_end;
 
         Ok(())
     }
 
-    // Define account structures
+    pub fn set_burn_policy(
+        ctx: Context<SetTokenTypeConfig>,
+        token_type: u8,
+        burn_frees_wallet_slot: bool,
+    ) -> ProgramResult {
+        ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
 
-    #[account]
-    pub struct UpgradeWeapon {
-        pub token_counter: [u64; 5],
-        pub token_type_counter: u64,
-        pub token_types: Vec<TokenType>,
-        pub token_type_uris: Vec<TokenTypeURI>,
-        pub name: String,
-        pub symbol: String,
-    }
+        let config = &mut ctx.accounts.token_type_config;
 
-    #[account]
-    pub struct MintedNFTAccount {
-        pub token_type: [u8; 5],
+        config.token_type = token_type;
+        config.burn_frees_wallet_slot = burn_frees_wallet_slot;
+
+        Ok(())
     }
 
-    #[derive(Accounts)]
-    pub struct MintToken<'info> {
-        /// CHECK: This is the token that we want to mint
-        #[account(mut)]
-        pub mint: Signer<'info>,
+    pub fn set_transfer_rules(
+        ctx: Context<SetTokenTypeConfig>,
+        token_type: u8,
+        soulbound: bool,
+        transfer_cooldown: i64,
+        transfer_fee: u64,
+    ) -> ProgramResult {
+        ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
 
-        /// CHECK: This is the token account that we want to mint tokens to
-        #[account(mut)]
-        pub token_account: UncheckedAccount<'info>,
+        if transfer_cooldown < 0 {
+            return Err(ErrorCode::InvalidTransferRules.into());
+        }
 
-        #[account(mut)]
-        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
-        /// CHECK: the authority of the mint account
-        #[account(mut)]
-        pub authority: Signer<'info>,
+        let config = &mut ctx.accounts.token_type_config;
 
-        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
-        pub owner: Signer<'info>,
+        config.token_type = token_type;
+        config.soulbound = soulbound;
+        config.transfer_cooldown = transfer_cooldown;
+        config.transfer_fee = transfer_fee;
 
-        /// CHECK: We will create this outside
-        #[account(mut)]
-        pub metadata_account: UncheckedAccount<'info>,
+        Ok(())
+    }
+
+    pub fn set_upgrade_limits(
+        ctx: Context<SetTokenTypeConfig>,
+        token_type: u8,
+        upgrade_interval: i64,
+        daily_upgrade_cap: u32,
+    ) -> ProgramResult {
+        ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
+
+        if upgrade_interval < 0 {
+            return Err(ErrorCode::InvalidUpgradeLimits.into());
+        }
+
+        let config = &mut ctx.accounts.token_type_config;
+
+        config.token_type = token_type;
+        config.upgrade_interval = upgrade_interval;
+        config.daily_upgrade_cap = daily_upgrade_cap;
+
+        Ok(())
+    }
+
+    pub fn set_repair_price(
+        ctx: Context<SetTokenTypeConfig>,
+        token_type: u8,
+        repair_price: u64,
+        repair_mint: Option<Pubkey>,
+    ) -> ProgramResult {
+        ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
+
+        let config = &mut ctx.accounts.token_type_config;
+
+        config.token_type = token_type;
+        config.repair_price = repair_price;
+        config.repair_mint = repair_mint;
+
+        Ok(())
+    }
+
+    pub fn set_level_table(
+        ctx: Context<SetTokenTypeConfig>,
+        token_type: u8,
+        levels: Vec<LevelUp>,
+        xp_grant_cap: u64,
+        xp_daily_cap: u64,
+    ) -> ProgramResult {
+        ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
+
+        if levels.len() > MAX_LEVEL_UPS || levels.windows(2).any(|w| w[0].xp >= w[1].xp) {
+            return Err(ErrorCode::InvalidLevelTable.into());
+        }
+
+        let config = &mut ctx.accounts.token_type_config;
+
+        config.token_type = token_type;
+        config.levels = levels;
+        config.xp_grant_cap = xp_grant_cap;
+        config.xp_daily_cap = xp_daily_cap;
+
+        Ok(())
+    }
+
+    pub fn set_rarity_tiers(
+        ctx: Context<SetTokenTypeConfig>,
+        token_type: u8,
+        rarity_tiers: Vec<RarityTier>,
+    ) -> ProgramResult {
+        ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
+
+        let total: u64 = rarity_tiers.iter().map(|t| t.weight as u64).sum();
+        if rarity_tiers.len() > RARITY_NAMES.len() || (!rarity_tiers.is_empty() && total == 0) {
+            return Err(ErrorCode::InvalidRarityTiers.into());
+        }
+
+        let config = &mut ctx.accounts.token_type_config;
+
+        config.token_type = token_type;
+        config.rarity_tiers = rarity_tiers;
+
+        Ok(())
+    }
+
+    pub fn set_sockets(
+        ctx: Context<SetTokenTypeConfig>,
+        token_type: u8,
+        sockets: u8,
+        unsocket_price: u64,
+    ) -> ProgramResult {
+        ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
+
+        if sockets as usize > MAX_SOCKETS {
+            return Err(ErrorCode::InvalidSocket.into());
+        }
+
+        let config = &mut ctx.accounts.token_type_config;
+
+        config.token_type = token_type;
+        config.sockets = sockets;
+        config.unsocket_price = unsocket_price;
+
+        Ok(())
+    }
+
+    /// Makes `token_type` a gem type, or with `gem_stats` `None` a weapon type
+    /// again.
+    pub fn set_gem_stats(
+        ctx: Context<SetTokenTypeConfig>,
+        token_type: u8,
+        gem_stats: Option<[u64; 5]>,
+    ) -> ProgramResult {
+        ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
+
+        let config = &mut ctx.accounts.token_type_config;
+
+        config.token_type = token_type;
+        config.is_gem = gem_stats.is_some();
+        config.gem_stats = gem_stats.unwrap_or_default();
+
+        Ok(())
+    }
+
+    pub fn set_token_type_uri(
+        ctx: Context<SetTokenTypeURI>,
+        token_type: u64,
+        token_uri: String,
+    ) -> ProgramResult {
+        let upgrade_weapon = &mut ctx.accounts.upgrade_weapon;
+
+        if token_type >= upgrade_weapon.token_type_counter {
+            return Err(ErrorCode::InvalidTokenType.into());
+        }
+
+        let index = upgrade_weapon
+            .token_type_uris
+            .iter()
+            .position(|t| t.id == token_type)
+            .unwrap();
+        upgrade_weapon.token_type_uris[index].token_uri = token_uri;
+
+        Ok(())
+    }
+
+    pub fn token_uri(ctx: Context<TokenURI>, token_id: u64) -> ProgramResult {
+        let upgrade_weapon = &ctx.accounts.upgrade_weapon;
+
+        if !upgrade_weapon.token_types.iter().any(|t| t.id == token_id) {
+            return Err(ErrorCode::InvalidTokenId.into());
+        }
+
+        let token_type = upgrade_weapon
+            .token_types
+            .iter()
+            .find(|t| t.id == token_id)
+            .unwrap()
+            .token_type;
+
+        let token_type_uri = upgrade_weapon
+            .token_type_uris
+            .iter()
+            .find(|t| t.id == token_type)
+            .unwrap()
+            .token_uri
+            .clone();
+
+        let token_account = &ctx.accounts.token_account;
+        if token_account.owner != ctx.accounts.user.key() {
+            return Err(ErrorCode::InvalidTokenOwner.into());
+        }
+
+        emit!(TokenURISuccess {
+            token_uri: token_type_uri
+        });
+
+        Ok(())
+    }
+
+    // Define account structures
+
+    #[account]
+    pub struct UpgradeWeapon {
+        pub token_counter: [u64; 5],
+        pub token_type_counter: u64,
+        /// One entry per weapon minted before `token_counter` and `live_supply`
+        /// took over. The account has a fixed size, so mints no longer add to it
+        /// and `token_uri` only knows these weapons.
+        pub token_types: Vec<TokenType>,
+        pub token_type_uris: Vec<TokenTypeURI>,
+        pub name: String,
+        pub symbol: String,
+        /// Weapons of each type currently in existence, i.e. minted and not burned.
+        pub live_supply: [u64; 5],
+        /// Whether `live_supply` counts every weapon, i.e. the deployment was
+        /// initialized with it or went through `migrate_live_supply`.
+        pub live_supply_migrated: bool,
+        /// Verified collection every weapon belongs to. Burning is refused until
+        /// it is set with `set_collection`.
+        pub collection_mint: Pubkey,
+    }
+
+    impl UpgradeWeapon {
+        /// Fails unless `token_type` was added and fits the per-type counters.
+        pub fn check_token_type(&self, token_type: u8) -> ProgramResult {
+            if token_type as u64 >= self.token_type_counter || token_type as usize >= self.token_counter.len() {
+                return Err(ErrorCode::InvalidTokenType.into());
+            }
+
+            Ok(())
+        }
+
+        /// Counts a new weapon of `token_type` against the type's supply, returning
+        /// the name and metadata URI of the NFT. A rolled `rarity` is added to the
+        /// end of the URI path.
+        pub fn record_mint(
+            &mut self,
+            token_type: u8,
+            weapon: &Pubkey,
+            rarity: Option<u8>,
+        ) -> std::result::Result<(String, String), ProgramError> {
+            let u64_token_type: u64 = token_type.into();
+
+            let token_type_uri = self
+                .token_type_uris
+                .iter()
+                .find(|t| t.id == u64_token_type)
+                .ok_or(ErrorCode::InvalidTokenType)?
+                .clone();
+
+            if token_type != 4 && token_type != 0 && self.token_counter[token_type as usize] >= 200 {
+                return Err(ErrorCode::NftLimitExceeded.into());
+            }
+
+            self.token_counter[token_type as usize] += 1;
+            self.live_supply[token_type as usize] += 1;
+
+            let mut uri = token_type_uri.token_uri
+                + "/2/"
+                + &weapon.to_string()
+                + "/"
+                + &self.token_counter[token_type as usize].to_string();
+
+            if let Some(rarity) = rarity {
+                uri = uri + "/" + RARITY_NAMES[rarity as usize];
+            }
+
+            Ok((token_type_uri.name, uri))
+        }
+
+        /// Fails when a collection is set and `collection_mint` is another one.
+        pub fn check_collection(&self, collection_mint: &Pubkey) -> ProgramResult {
+            if self.collection_mint != Pubkey::default() && self.collection_mint != *collection_mint {
+                return Err(ErrorCode::NotInCollection.into());
+            }
+
+            Ok(())
+        }
+
+        /// Fails unless `metadata` is the Metaplex metadata of `mint` and puts it
+        /// in this program's verified collection.
+        pub fn check_collection_member(&self, metadata: &AccountInfo, mint: &Pubkey) -> ProgramResult {
+            let (metadata_key, _) = mpl_token_metadata::pda::find_metadata_account(mint);
+
+            if metadata.key() != metadata_key || metadata.owner != &mpl_token_metadata::ID {
+                return Err(ErrorCode::NotInCollection.into());
+            }
+
+            let metadata = Metadata::from_account_info(metadata)?;
+
+            match metadata.collection {
+                Some(collection)
+                    if collection.verified
+                        && collection.key == self.collection_mint
+                        && self.collection_mint != Pubkey::default() =>
+                {
+                    Ok(())
+                }
+                _ => Err(ErrorCode::NotInCollection.into()),
+            }
+        }
+
+        /// Takes a burned weapon of `token_type` out of the live supply.
+        /// `token_counter` keeps counting every weapon ever minted.
+        pub fn record_burn(&mut self, token_type: u8) {
+            let live_supply = &mut self.live_supply[token_type as usize];
+            *live_supply = live_supply.saturating_sub(1);
+        }
+    }
+
+    /// Per-wallet 0/1 flags from before mint limits were configurable. Nothing
+    /// writes these anymore, but a set flag still counts as one mint.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct MintedNFTAccount {
+        pub token_type: [u8; 5],
+    }
+
+    impl MintedNFTAccount {
+        pub fn legacy_minted(nft_account: &AccountInfo, token_type: u8) -> std::result::Result<u32, ProgramError> {
+            if nft_account.owner != &crate::ID || nft_account.data_is_empty() {
+                return Ok(0);
+            }
+
+            let legacy = MintedNFTAccount::try_deserialize(&mut &nft_account.try_borrow_data()?[..])?;
+
+            Ok(legacy.token_type[token_type as usize].into())
+        }
+    }
+
+    /// Mint rules for one token type of an `UpgradeWeapon`.
+    ///
+    /// Player mints fall back to `load_or_default` when a type has none yet,
+    /// but every other instruction taking a config requires the account: on
+    /// existing deployments, run `set_mint_limits` for each type right after
+    /// upgrading the program, before airdrops, vouchers, upgrades or trading.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct TokenTypeConfig {
+        pub token_type: u8,
+        /// Mints allowed per wallet over its lifetime, 0 for unlimited.
+        pub wallet_limit: u32,
+        /// Mints allowed per wallet within `window_seconds`, 0 for unlimited.
+        pub window_limit: u32,
+        pub window_seconds: i64,
+        /// Sale phases, e.g. OG, whitelist and public. Empty means the type is
+        /// always on sale at its default price.
+        #[max_len(4)]
+        pub phases: Vec<MintPhase>,
+        /// Unix timestamps bounding when the type can be minted at all, for
+        /// seasonal drops that close on their own.
+        pub mint_start: Option<i64>,
+        pub mint_end: Option<i64>,
+        /// Whether burning a weapon gives its minter back one mint of this type.
+        /// The slot always goes back to the wallet that minted, whoever burns:
+        /// `wallet_limit` caps what a wallet mints, not what it holds, so buying
+        /// and burning someone else's weapon mustn't raise the buyer's cap.
+        pub burn_frees_wallet_slot: bool,
+        /// Weapons of this type can't change hands through `transfer` at all.
+        pub soulbound: bool,
+        /// Seconds after an upgrade before the weapon can be transferred, so
+        /// freshly boosted stats can't be flipped straight away.
+        pub transfer_cooldown: i64,
+        /// Lamports the sender pays the treasury on every transfer.
+        pub transfer_fee: u64,
+        /// Seconds a weapon must wait between two upgrades.
+        pub upgrade_interval: i64,
+        /// Upgrades allowed per wallet per UTC day, 0 for unlimited.
+        pub daily_upgrade_cap: u32,
+        /// Price of restoring one point of durability, in lamports or, when
+        /// `repair_mint` is set, in base units of that token.
+        pub repair_price: u64,
+        pub repair_mint: Option<Pubkey>,
+        /// XP needed for each level and the stats it adds. Entry `n` takes a
+        /// weapon from level `n` to `n + 1`. Empty means the type has no XP
+        /// progression; otherwise upgrades can't set its level or stats.
+        #[max_len(10)]
+        pub levels: Vec<LevelUp>,
+        /// Most XP a single `grant_xp` can give, 0 for unlimited.
+        pub xp_grant_cap: u64,
+        /// Most XP a weapon can gain per UTC day, 0 for unlimited.
+        pub xp_daily_cap: u64,
+        /// Tiers from common to legendary, indexed like `RARITY_NAMES`. Empty
+        /// means weapons of the type aren't rolled.
+        #[max_len(5)]
+        pub rarity_tiers: Vec<RarityTier>,
+        /// Sockets on weapons of this type, at most `MAX_SOCKETS`.
+        pub sockets: u8,
+        /// Lamports the treasury charges to take a gem back out.
+        pub unsocket_price: u64,
+        /// Whether this type is a gem rather than a weapon.
+        pub is_gem: bool,
+        /// What a gem of this type adds to hp, damage, mana, mp regen and attack
+        /// speed while socketed.
+        pub gem_stats: [u64; 5],
+    }
+
+    /// Must match the `max_len` of `TokenTypeConfig::rarity_tiers`.
+    pub const RARITY_NAMES: [&str; 5] = ["common", "uncommon", "rare", "epic", "legendary"];
+
+    #[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+    pub struct RarityTier {
+        /// Relative odds of rolling the tier.
+        pub weight: u32,
+        /// Extra stats a weapon of this tier gains on every level-up, in basis
+        /// points of the level's stats.
+        pub stat_bonus_bps: u32,
+    }
+
+    /// Must match the `max_len` of `TokenTypeConfig::levels`.
+    pub const MAX_LEVEL_UPS: usize = 10;
+
+    #[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+    pub struct LevelUp {
+        /// Total XP at which the level is reached.
+        pub xp: u64,
+        /// Added to hp, damage, mana, mp regen and attack speed, in that order.
+        pub stats: [u64; 5],
+    }
+
+    pub const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+    /// Upgrades of one token type a wallet made on `day`, counted in days since
+    /// the Unix epoch.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct UpgradeCounter {
+        pub day: i64,
+        pub upgrades: u32,
+    }
+
+    /// Must match the `max_len` of `TokenTypeConfig::phases`.
+    pub const MAX_MINT_PHASES: usize = 4;
+
+    #[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+    pub struct MintPhase {
+        pub start: i64,
+        pub end: i64,
+        /// Root of a Merkle tree of keccak(wallet) leaves, all zeros for a
+        /// phase open to every wallet.
+        pub merkle_root: [u8; 32],
+        pub price: u64,
+        /// Mints allowed per wallet during this phase, 0 for unlimited.
+        pub wallet_allocation: u32,
+    }
+
+    impl MintPhase {
+        pub fn check_allowed(&self, wallet: &Pubkey, proof: &[[u8; 32]]) -> ProgramResult {
+            if self.merkle_root == [0; 32] {
+                return Ok(());
+            }
+
+            let leaf = solana_program::keccak::hash(wallet.as_ref()).to_bytes();
+            if !crate::verify_merkle_proof(&self.merkle_root, leaf, proof) {
+                return Err(ErrorCode::NotAllowlisted.into());
+            }
+
+            Ok(())
+        }
+
+        pub fn record_mint(&self, counter: &mut MintCounter) -> ProgramResult {
+            if self.wallet_allocation != 0 && counter.minted >= self.wallet_allocation {
+                return Err(ErrorCode::PhaseAllocationExceeded.into());
+            }
+
+            counter.minted += 1;
+
+            Ok(())
+        }
+    }
+
+    impl TokenTypeConfig {
+        /// Loads the config of `token_type` from an account Anchor didn't check.
+        pub fn load(
+            info: &AccountInfo,
+            upgrade_weapon: &Pubkey,
+            token_type: u8,
+        ) -> std::result::Result<TokenTypeConfig, ProgramError> {
+            let (address, _) = Pubkey::find_program_address(
+                &[b"tokenType", upgrade_weapon.as_ref(), &[token_type]],
+                &crate::ID,
+            );
+
+            if info.owner != &crate::ID || info.key() != address {
+                return Err(ErrorCode::InvalidBatchAccounts.into());
+            }
+
+            Ok(TokenTypeConfig::try_deserialize(&mut &info.try_borrow_data()?[..])?)
+        }
+
+        /// Same as `load`, but a type the admin never configured mints with the
+        /// rules it had before configs existed: one per wallet, no phases and
+        /// no window, so existing types keep working right after the upgrade.
+        pub fn load_or_default(
+            info: &AccountInfo,
+            upgrade_weapon: &Pubkey,
+            token_type: u8,
+        ) -> std::result::Result<TokenTypeConfig, ProgramError> {
+            if info.owner == &crate::ID {
+                return TokenTypeConfig::load(info, upgrade_weapon, token_type);
+            }
+
+            let (address, _) = Pubkey::find_program_address(
+                &[b"tokenType", upgrade_weapon.as_ref(), &[token_type]],
+                &crate::ID,
+            );
+
+            if info.key() != address || !info.data_is_empty() {
+                return Err(ErrorCode::InvalidBatchAccounts.into());
+            }
+
+            Ok(TokenTypeConfig {
+                token_type,
+                wallet_limit: 1,
+                window_limit: 0,
+                window_seconds: 0,
+                phases: vec![],
+                mint_start: None,
+                mint_end: None,
+                burn_frees_wallet_slot: false,
+                soulbound: false,
+                transfer_cooldown: 0,
+                transfer_fee: 0,
+                upgrade_interval: 0,
+                daily_upgrade_cap: 0,
+                repair_price: 0,
+                repair_mint: None,
+                levels: vec![],
+                xp_grant_cap: 0,
+                xp_daily_cap: 0,
+                rarity_tiers: vec![],
+                sockets: 0,
+                unsocket_price: 0,
+                is_gem: false,
+                gem_stats: [0; 5],
+            })
+        }
+
+        /// Counts one more mint by the wallet owning `counter`, failing if that
+        /// goes over any of this type's limits.
+        pub fn record_mint(&self, counter: &mut MintCounter, legacy_minted: u32, now: i64) -> ProgramResult {
+            self.check_mint_window(now)?;
+
+            if self.wallet_limit != 0 && counter.minted + legacy_minted >= self.wallet_limit {
+                return Err(ErrorCode::WalletLimitExceeded.into());
+            }
+
+            if self.window_limit != 0 {
+                let window_end = counter
+                    .window_started_at
+                    .checked_add(self.window_seconds)
+                    .ok_or(ErrorCode::InvalidMintLimit)?;
+
+                if now >= window_end {
+                    counter.window_started_at = now;
+                    counter.window_minted = 0;
+                }
+
+                if counter.window_minted >= self.window_limit {
+                    return Err(ErrorCode::WindowLimitExceeded.into());
+                }
+
+                counter.window_minted += 1;
+            }
+
+            counter.minted += 1;
+
+            Ok(())
+        }
+
+        pub fn check_mint_window(&self, now: i64) -> ProgramResult {
+            if matches!(self.mint_start, Some(start) if now < start) {
+                return Err(ErrorCode::MintNotStarted.into());
+            }
+
+            if matches!(self.mint_end, Some(end) if now >= end) {
+                return Err(ErrorCode::MintEnded.into());
+            }
+
+            Ok(())
+        }
+
+        /// Applies the rules of the sale phase picked by the caller to one more
+        /// mint by `wallet`, returning what it has to pay.
+        pub fn record_phase_mint(
+            &self,
+            phase: u8,
+            wallet: &Pubkey,
+            proof: &[[u8; 32]],
+            phase_counter: Option<&mut MintCounter>,
+            now: i64,
+        ) -> std::result::Result<u64, ProgramError> {
+            match self.active_phase(phase, now)? {
+                Some(mint_phase) => {
+                    mint_phase.check_allowed(wallet, proof)?;
+                    mint_phase.record_mint(phase_counter.ok_or(ErrorCode::InvalidMintPhase)?)?;
+                    Ok(mint_phase.price)
+                }
+                None if self.token_type != 0 && self.token_type != 4 => Ok(100000000),
+                None => Ok(0),
+            }
+        }
+
+        /// The phase a mint at `now` falls in, which must be the one the caller
+        /// picked. `None` when the type has no phases at all.
+        pub fn active_phase(&self, phase: u8, now: i64) -> std::result::Result<Option<&MintPhase>, ProgramError> {
+            if self.phases.is_empty() {
+                return Ok(None);
+            }
+
+            match self.phases.get(phase as usize) {
+                Some(p) if p.start <= now && now < p.end => Ok(Some(p)),
+                _ => Err(ErrorCode::NoActivePhase.into()),
+            }
+        }
+
+        /// Checks the type's upgrade limits for `weapon` and counts the upgrade
+        /// against the holder's daily cap.
+        pub fn record_upgrade(&self, weapon: &Weapon, counter: &mut UpgradeCounter, now: i64) -> ProgramResult {
+            if weapon.last_upgraded_at != 0 && now < weapon.last_upgraded_at.saturating_add(self.upgrade_interval) {
+                return Err(ErrorCode::UpgradeCooldown.into());
+            }
+
+            let day = now.div_euclid(SECONDS_PER_DAY);
+            if counter.day != day {
+                counter.day = day;
+                counter.upgrades = 0;
+            }
+
+            if self.daily_upgrade_cap != 0 && counter.upgrades >= self.daily_upgrade_cap {
+                return Err(ErrorCode::DailyUpgradeCapReached.into());
+            }
+
+            counter.upgrades += 1;
+
+            Ok(())
+        }
+
+        /// Adds `amount` XP to `weapon` within the type's caps and applies every
+        /// level-up it crosses.
+        pub fn grant_xp(&self, weapon: &mut Weapon, amount: u64, now: i64) -> ProgramResult {
+            if self.xp_grant_cap != 0 && amount > self.xp_grant_cap {
+                return Err(ErrorCode::XpCapExceeded.into());
+            }
+
+            let day = now.div_euclid(SECONDS_PER_DAY);
+            if weapon.xp_day != day {
+                weapon.xp_day = day;
+                weapon.xp_today = 0;
+            }
+
+            let xp_today = weapon.xp_today.saturating_add(amount);
+            if self.xp_daily_cap != 0 && xp_today > self.xp_daily_cap {
+                return Err(ErrorCode::XpCapExceeded.into());
+            }
+
+            weapon.xp_today = xp_today;
+            weapon.xp = weapon.xp.saturating_add(amount);
+
+            let mut base_stats = weapon.base_stats;
+
+            while let Some(level_up) = self.levels.get(weapon.level as usize) {
+                if weapon.xp < level_up.xp {
+                    break;
+                }
+
+                for (stat, gain) in base_stats.iter_mut().zip(level_up.stats) {
+                    *stat = stat.saturating_add(gain);
+                }
+                weapon.level += 1;
+            }
+
+            weapon.set_base_stats(self, base_stats);
+
+            Ok(())
+        }
+
+        /// Picks a rarity tier by weight from `seed`, or `None` if the type has
+        /// no tiers.
+        pub fn roll_rarity(&self, seed: &[u8; 32]) -> Option<u8> {
+            let total: u64 = self.rarity_tiers.iter().map(|t| t.weight as u64).sum();
+            if total == 0 {
+                return None;
+            }
+
+            let mut roll = u64::from_le_bytes(seed[..8].try_into().unwrap()) % total;
+
+            for (tier, rarity) in self.rarity_tiers.iter().enumerate() {
+                if roll < rarity.weight as u64 {
+                    return Some(tier as u8);
+                }
+                roll -= rarity.weight as u64;
+            }
+
+            None
+        }
+
+        /// Rolls the rarity of the weapon `mint` from a `seed` our backend signed
+        /// for it in an Ed25519 instruction earlier in the transaction. Players
+        /// can't bias it by grinding mint keypairs or picking the slot they land
+        /// in, as long as the backend hands out one seed per mint request.
+        /// Types without tiers need no signature.
+        pub fn roll_signed_rarity(
+            &self,
+            instructions: &AccountInfo,
+            mint: &Pubkey,
+            seed: &[u8; 32],
+        ) -> std::result::Result<Option<u8>, ProgramError> {
+            if self.rarity_tiers.is_empty() {
+                return Ok(None);
+            }
+
+            crate::verify_ed25519_instruction(
+                instructions,
+                &solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"),
+                &crate::rarity_seed_message(mint, seed),
+            )?;
+
+            Ok(self.roll_rarity(&crate::rarity_seed(seed, mint)))
+        }
+
+        /// Scales a stat by the bonus of the `rarity` tier.
+        fn with_rarity_bonus(&self, rarity: u8, stat: u64) -> u64 {
+            let bonus_bps = self
+                .rarity_tiers
+                .get(rarity as usize)
+                .map_or(0, |tier| tier.stat_bonus_bps);
+
+            stat.saturating_add((stat as u128 * bonus_bps as u128 / 10_000) as u64)
+        }
+
+        /// Fails for types that level up through XP: their level and stats only
+        /// come from the level table, through `grant_xp`.
+        pub fn check_direct_upgrade(&self) -> ProgramResult {
+            if !self.levels.is_empty() {
+                return Err(ErrorCode::StatsFromXpOnly.into());
+            }
+
+            Ok(())
+        }
+
+        /// Checks the type's transfer rules for `weapon`, returning the fee owed.
+        pub fn check_transfer(&self, weapon: &Weapon, now: i64) -> std::result::Result<u64, ProgramError> {
+            if self.soulbound {
+                return Err(ErrorCode::Soulbound.into());
+            }
+
+            if now < weapon.last_upgraded_at.saturating_add(self.transfer_cooldown) {
+                return Err(ErrorCode::TransferCooldown.into());
+            }
+
+            Ok(self.transfer_fee)
+        }
+    }
+
+    /// How many weapons of one token type a wallet has minted, overall or, for
+    /// counters seeded with a phase index, within that phase.
+    #[account]
+    #[derive(InitSpace, Default)]
+    pub struct MintCounter {
+        pub minted: u32,
+        pub window_started_at: i64,
+        pub window_minted: u32,
+    }
+
+    impl MintCounter {
+        /// Loads the counter PDA derived from `seeds`, creating it on the wallet's
+        /// first mint. For instructions that can't let Anchor do it, like
+        /// `mint_batch`.
+        pub fn load_or_create<'info>(
+            info: &AccountInfo<'info>,
+            payer: &AccountInfo<'info>,
+            system_program: &AccountInfo<'info>,
+            seeds: &[&[u8]],
+        ) -> std::result::Result<MintCounter, ProgramError> {
+            if info.owner == &system_program::ID {
+                crate::create_pda_account(info, payer, system_program, seeds, 8 + MintCounter::INIT_SPACE)?;
+                return Ok(MintCounter::default());
+            }
+
+            if info.owner != &crate::ID || info.key() != Pubkey::find_program_address(seeds, &crate::ID).0 {
+                return Err(ErrorCode::InvalidBatchAccounts.into());
+            }
+
+            Ok(MintCounter::try_deserialize(&mut &info.try_borrow_data()?[..])?)
+        }
+
+        pub fn store(&self, info: &AccountInfo) -> ProgramResult {
+            self.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+
+            Ok(())
+        }
+    }
+
+    /// How a weapon came to be minted.
+    #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, InitSpace)]
+    pub enum MintSource {
+        Mint,
+        Batch,
+        Airdrop,
+        Voucher,
+        /// Minted before records existed and backfilled by `migrate_weapon`.
+        Legacy,
+    }
+
+    /// Provenance of one weapon NFT, keyed by its mint.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct MintRecord {
+        pub token_type: u8,
+        pub source: MintSource,
+        /// Wallet the weapon was minted to, unknown for `MintSource::Legacy`.
+        pub minter: Pubkey,
+        /// Lamports paid for it.
+        pub price: u64,
+        /// 0 for `MintSource::Legacy`.
+        pub minted_at: i64,
+    }
+
+    /// A claimable reward, good for `uses` weapons of `token_type`.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct Voucher {
+        pub token_type: u8,
+        /// The only wallet that can redeem it, or anyone for `None`. A voucher
+        /// with a recipient has a single use.
+        pub recipient: Option<Pubkey>,
+        pub expires_at: Option<i64>,
+        pub uses: u32,
+    }
+
+    /// Marks that a wallet redeemed a voucher, so it can't do it twice.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct VoucherRedemption {
+        pub redeemed_at: i64,
+    }
+
+    /// Free token types claimed from one device, keyed by the hash of its id.
+    #[account]
+    #[derive(InitSpace, Default)]
+    pub struct DeviceAccount {
+        pub token_type: [u8; 5],
+    }
+
+    impl DeviceAccount {
+        /// Loads the account of the device with id hash `device_hash`, creating
+        /// it on the device's first free mint.
+        pub fn load_or_create<'info>(
+            info: &AccountInfo<'info>,
+            payer: &AccountInfo<'info>,
+            system_program: &AccountInfo<'info>,
+            device_hash: &[u8; 32],
+        ) -> std::result::Result<DeviceAccount, ProgramError> {
+            if info.owner == &system_program::ID {
+                crate::create_pda_account(
+                    info,
+                    payer,
+                    system_program,
+                    &[b"device", device_hash.as_ref()],
+                    8 + DeviceAccount::INIT_SPACE,
+                )?;
+                return Ok(DeviceAccount::default());
+            }
+
+            if info.owner != &crate::ID {
+                return Err(ProgramError::IllegalOwner);
+            }
+
+            Ok(DeviceAccount::try_deserialize(&mut &info.try_borrow_data()?[..])?)
+        }
+
+        pub fn store(&self, info: &AccountInfo) -> ProgramResult {
+            self.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+
+            Ok(())
+        }
+    }
+
+    #[derive(Accounts)]
+    #[instruction(token_type: u8, device: TokenDevice, phase: u8)]
+    pub struct MintToken<'info> {
+        /// CHECK: This is the token that we want to mint
+        #[account(mut)]
+        pub mint: Signer<'info>,
+
+        /// CHECK: This is the token account that we want to mint tokens to
+        #[account(mut)]
+        pub token_account: UncheckedAccount<'info>,
+
+        #[account(mut)]
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+        /// CHECK: the authority of the mint account
+        #[account(mut)]
+        pub authority: Signer<'info>,
+
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub owner: Signer<'info>,
+
+        /// CHECK: We will create this outside
+        #[account(mut)]
+        pub metadata_account: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        #[account(
+            init,
+            payer = authority,
+            space = 8 + Weapon::INIT_SPACE, seeds = [b"weapon", mint.key().as_ref()], bump
+        )]
+        pub weapon_account: Account<'info, Weapon>,
+
+        #[account(
+            init,
+            payer = authority,
+            space = 8 + MintRecord::INIT_SPACE, seeds = [b"mintRecord", mint.key().as_ref()], bump
+        )]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        /// CHECK: We will create this outside
+        #[account(mut)]
+        pub edition: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        #[account(mut)]
+        pub collection: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        pub collection_mint: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        pub collection_master_edition: UncheckedAccount<'info>,
+
+        pub rent: Sysvar<'info, Rent>,
+
+        /// CHECK: legacy per-wallet flags, read by hand since most wallets never had one
+        #[account(seeds = [b"mintedNFT", authority.key().as_ref()], bump)]
+        pub nft_account: UncheckedAccount<'info>,
+
+        /// CHECK: read by hand so types the admin never configured still mint,
+        /// see `TokenTypeConfig::load_or_default`
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[token_type]], bump)]
+        pub token_type_config: UncheckedAccount<'info>,
+
+        #[account(
+            init_if_needed,
+            payer = authority,
+            space = 8 + MintCounter::INIT_SPACE,
+            seeds = [b"mintCounter", upgrade_weapon.key().as_ref(), authority.key().as_ref(), &[token_type]], bump
+        )]
+        pub mint_counter: Box<Account<'info, MintCounter>>,
+
+        /// CHECK: only created and read for types with phases, see `mint`
+        #[account(
+            mut,
+            seeds = [b"mintCounter", upgrade_weapon.key().as_ref(), authority.key().as_ref(), &[token_type], &[phase]], bump
+        )]
+        pub phase_counter: UncheckedAccount<'info>,
+
+        /// CHECK: only created and written on free mints, see `mint`
+        #[account(mut, seeds = [b"device", device.hash().as_ref()], bump)]
+        pub device_account: UncheckedAccount<'info>,
+
+        /// CHECK: PDA that freezes soulbound weapons, holds no data
+        #[account(seeds = [b"freezeAuthority"], bump)]
+        pub freeze_authority: UncheckedAccount<'info>,
+
+        /// CHECK: the instructions sysvar, used to find the device attestation on
+        /// free mints and the rarity seed's signature
+        #[account(address = solana_program::sysvar::instructions::ID)]
+        pub instructions: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        pub system_program: Program<'info, System>,
+        /// CHECK: We will create this outside
+        pub token_program: Program<'info, Token>,
+        /// CHECK: We will create this outside
+        pub token_metadata_program: UncheckedAccount<'info>,
+        pub associated_token_program: Program<'info, anchor_spl::associated_token::AssociatedToken>,
+    }
+
+    #[derive(Accounts)]
+    #[instruction(token_type: u8)]
+    pub struct Airdrop<'info> {
+        #[account(mut)]
+        pub mint: Signer<'info>,
+
+        /// CHECK: the recipient's associated token account, created by the mint pipeline
+        #[account(mut)]
+        pub token_account: UncheckedAccount<'info>,
+
+        #[account(mut)]
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+
+        /// CHECK: any wallet can receive an airdrop
+        pub recipient: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        #[account(mut)]
+        pub metadata_account: UncheckedAccount<'info>,
+
+        #[account(
+            init,
+            payer = authority,
+            space = 8 + Weapon::INIT_SPACE, seeds = [b"weapon", mint.key().as_ref()], bump
+        )]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        #[account(
+            init,
+            payer = authority,
+            space = 8 + MintRecord::INIT_SPACE, seeds = [b"mintRecord", mint.key().as_ref()], bump
+        )]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        /// CHECK: We will create this outside
+        #[account(mut)]
+        pub edition: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        #[account(mut)]
+        pub collection: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        pub collection_mint: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        pub collection_master_edition: UncheckedAccount<'info>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        pub rent: Sysvar<'info, Rent>,
+        pub system_program: Program<'info, System>,
+        pub token_program: Program<'info, Token>,
+        /// CHECK: We will create this outside
+        pub token_metadata_program: UncheckedAccount<'info>,
+        pub associated_token_program: Program<'info, anchor_spl::associated_token::AssociatedToken>,
+    }
+
+    #[derive(Accounts)]
+    pub struct SetCollection<'info> {
+        #[account(mut)]
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+    }
+
+    #[derive(Accounts)]
+    pub struct Refund<'info> {
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+
+        /// CHECK: only used to find the mint record
+        pub mint: UncheckedAccount<'info>,
+
+        #[account(mut, seeds = [b"mintRecord", mint.key().as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        /// CHECK: must be the wallet the mint record says paid
+        #[account(mut, address = mint_record.minter)]
+        pub minter: UncheckedAccount<'info>,
+
+        pub system_program: Program<'info, System>,
+    }
+
+    #[derive(Accounts)]
+    pub struct Unbind<'info> {
+        #[account(address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+
+        pub mint: Account<'info, anchor_spl::token::Mint>,
+
+        #[account(mut, token::mint = mint)]
+        pub token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        /// CHECK: the mint's master edition, checked by Metaplex
+        pub edition: UncheckedAccount<'info>,
+
+        /// CHECK: PDA that freezes soulbound weapons, holds no data
+        #[account(seeds = [b"freezeAuthority"], bump)]
+        pub freeze_authority: UncheckedAccount<'info>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(seeds = [b"weapon", mint.key().as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        #[account(seeds = [b"mintRecord", mint.key().as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        /// CHECK: must not exist, since staked weapons are frozen by the same PDA
+        #[account(seeds = [b"stake", mint.key().as_ref()], bump)]
+        pub stake_account: UncheckedAccount<'info>,
+
+        /// CHECK: must not exist, since lent weapons are frozen by the same PDA
+        #[account(seeds = [b"rental", mint.key().as_ref()], bump)]
+        pub rental: UncheckedAccount<'info>,
+
+        pub token_program: Program<'info, Token>,
+
+        /// CHECK: must be Metaplex, which does the thaw
+        #[account(address = mpl_token_metadata::ID)]
+        pub token_metadata_program: UncheckedAccount<'info>,
+    }
+
+    #[derive(Accounts)]
+    #[instruction(id: u64)]
+    pub struct CreateVoucher<'info> {
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(
+            init,
+            payer = authority,
+            space = 8 + Voucher::INIT_SPACE,
+            seeds = [b"voucher", upgrade_weapon.key().as_ref(), &id.to_le_bytes()], bump
+        )]
+        pub voucher: Box<Account<'info, Voucher>>,
+
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+
+        pub system_program: Program<'info, System>,
+    }
+
+    #[derive(Accounts)]
+    #[instruction(id: u64)]
+    pub struct RedeemVoucher<'info> {
+        #[account(
+            mut,
+            seeds = [b"voucher", upgrade_weapon.key().as_ref(), &id.to_le_bytes()], bump
+        )]
+        pub voucher: Box<Account<'info, Voucher>>,
+
+        #[account(
+            init,
+            payer = authority,
+            space = 8 + VoucherRedemption::INIT_SPACE,
+            seeds = [b"voucherRedemption", voucher.key().as_ref(), authority.key().as_ref()], bump
+        )]
+        pub redemption: Box<Account<'info, VoucherRedemption>>,
+
+        #[account(mut)]
+        pub mint: Signer<'info>,
+
+        /// CHECK: the player's associated token account, created by the mint pipeline
+        #[account(mut)]
+        pub token_account: UncheckedAccount<'info>,
+
+        #[account(mut)]
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(mut)]
+        pub authority: Signer<'info>,
+
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub owner: Signer<'info>,
+
+        /// CHECK: We will create this outside
+        #[account(mut)]
+        pub metadata_account: UncheckedAccount<'info>,
+
+        #[account(
+            init,
+            payer = authority,
+            space = 8 + Weapon::INIT_SPACE, seeds = [b"weapon", mint.key().as_ref()], bump
+        )]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        #[account(
+            init,
+            payer = authority,
+            space = 8 + MintRecord::INIT_SPACE, seeds = [b"mintRecord", mint.key().as_ref()], bump
+        )]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        /// CHECK: We will create this outside
+        #[account(mut)]
+        pub edition: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        #[account(mut)]
+        pub collection: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        pub collection_mint: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        pub collection_master_edition: UncheckedAccount<'info>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[voucher.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        /// CHECK: PDA that freezes soulbound weapons, holds no data
+        #[account(seeds = [b"freezeAuthority"], bump)]
+        pub freeze_authority: UncheckedAccount<'info>,
+
+        /// CHECK: the instructions sysvar, used to find the rarity seed's signature
+        #[account(address = solana_program::sysvar::instructions::ID)]
+        pub instructions: UncheckedAccount<'info>,
+
+        pub rent: Sysvar<'info, Rent>,
+        pub system_program: Program<'info, System>,
+        pub token_program: Program<'info, Token>,
+        /// CHECK: We will create this outside
+        pub token_metadata_program: UncheckedAccount<'info>,
+        pub associated_token_program: Program<'info, anchor_spl::associated_token::AssociatedToken>,
+    }
+
+    /// Most weapons a single `mint_batch` can create.
+    pub const MAX_MINT_BATCH: usize = 3;
+
+    /// Remaining accounts per weapon in `mint_batch`.
+    pub const MINT_BATCH_ACCOUNTS: usize = 9;
+
+    #[derive(Accounts)]
+    pub struct MintBatch<'info> {
+        #[account(mut)]
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(mut)]
+        pub authority: Signer<'info>,
+
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub owner: Signer<'info>,
+
+        /// CHECK: We will create this outside
+        #[account(mut)]
+        pub collection: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        pub collection_mint: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        pub collection_master_edition: UncheckedAccount<'info>,
+
+        /// CHECK: legacy per-wallet flags, read by hand since most wallets never had one
+        #[account(seeds = [b"mintedNFT", authority.key().as_ref()], bump)]
+        pub nft_account: UncheckedAccount<'info>,
+
+        /// CHECK: PDA that freezes soulbound weapons, holds no data
+        #[account(seeds = [b"freezeAuthority"], bump)]
+        pub freeze_authority: UncheckedAccount<'info>,
+
+        /// CHECK: the instructions sysvar, used to find the rarity seed's signature
+        #[account(address = solana_program::sysvar::instructions::ID)]
+        pub instructions: UncheckedAccount<'info>,
+
+        pub rent: Sysvar<'info, Rent>,
+        pub system_program: Program<'info, System>,
+        pub token_program: Program<'info, Token>,
+        /// CHECK: We will create this outside
+        pub token_metadata_program: UncheckedAccount<'info>,
+        pub associated_token_program: Program<'info, anchor_spl::associated_token::AssociatedToken>,
+        // Then, per weapon: mint (signer), token_account, metadata_account,
+        // edition, weapon_account, token_type_config, mint_counter,
+        // phase_counter and mint_record, as on `MintToken`.
+    }
+
+    /// Accounts every minting instruction passes through to the token and
+    /// metadata programs.
+    pub struct MintPipeline<'info> {
+        /// Pays for everything and is the mint authority.
+        pub authority: AccountInfo<'info>,
+        /// Wallet receiving the NFT.
+        pub recipient: AccountInfo<'info>,
+        pub owner: AccountInfo<'info>,
+        pub collection: AccountInfo<'info>,
+        pub collection_mint: AccountInfo<'info>,
+        pub collection_master_edition: AccountInfo<'info>,
+        pub rent: AccountInfo<'info>,
+        pub system_program: AccountInfo<'info>,
+        pub token_program: AccountInfo<'info>,
+    }
+
+    /// Accounts of the one weapon being minted.
+    pub struct MintItem<'info> {
+        pub mint: AccountInfo<'info>,
+        pub token_account: AccountInfo<'info>,
+        pub metadata_account: AccountInfo<'info>,
+        pub edition: AccountInfo<'info>,
+        pub weapon_account: AccountInfo<'info>,
+    }
+
+    impl<'info> MintPipeline<'info> {
+        /// Creates the mint and the recipient's token account, and mints the
+        /// single token into it.
+        pub fn create_token(&self, item: &MintItem<'info>) -> ProgramResult {
+            anchor_lang::system_program::create_account(
+                CpiContext::new(
+                    self.token_program.clone(),
+                    system_program::CreateAccount {
+                        from: self.authority.clone(),
+                        to: item.mint.clone(),
+                    },
+                ),
+                10000000,
+                82,
+                self.token_program.key,
+            )?;
+
+            anchor_spl::token::initialize_mint(
+                CpiContext::new(
+                    self.token_program.clone(),
+                    anchor_spl::token::InitializeMint {
+                        mint: item.mint.clone(),
+                        rent: self.rent.clone(),
+                    },
+                ),
+                0,
+                self.authority.key,
+                Some(self.authority.key),
+            )?;
+
+            anchor_spl::associated_token::create(CpiContext::new(
+                item.token_account.clone(),
+                anchor_spl::associated_token::Create {
+                    payer: self.authority.clone(),
+                    associated_token: item.token_account.clone(),
+                    authority: self.recipient.clone(),
+                    mint: item.mint.clone(),
+                    system_program: self.system_program.clone(),
+                    token_program: self.token_program.clone(),
+                },
+            ))?;
+
+            // Create the MintTo struct for our context
+            let cpi_accounts = MintTo {
+                mint: item.mint.clone(),
+                to: item.token_account.clone(),
+                authority: self.authority.clone(),
+            };
+
+            let cpi_ctx = CpiContext::new(self.token_program.clone(), cpi_accounts);
+
+            // Execute anchor's helper function to mint tokens
+            anchor_spl::token::mint_to(cpi_ctx, 1)?;
+
+            Ok(())
+        }
+
+        /// Freezes the recipient's token account for a soulbound weapon. The master
+        /// edition holds the mint's freeze authority, so `freeze_authority` is made
+        /// the account's delegate and freezes it through Metaplex. The recipient
+        /// must sign.
+        pub fn bind(&self, item: &MintItem<'info>, freeze_authority: &AccountInfo<'info>) -> ProgramResult {
+            anchor_spl::token::approve(
+                CpiContext::new(
+                    self.token_program.clone(),
+                    anchor_spl::token::Approve {
+                        to: item.token_account.clone(),
+                        delegate: freeze_authority.clone(),
+                        authority: self.recipient.clone(),
+                    },
+                ),
+                1,
+            )?;
+
+            crate::set_weapon_frozen(
+                true,
+                freeze_authority,
+                &item.token_account,
+                &item.edition,
+                &item.mint,
+                &self.token_program,
+            )
+        }
+
+        pub fn pay(&self, price: u64) -> ProgramResult {
+            if price == 0 {
+                return Ok(());
+            }
+
+            let cpi_context = CpiContext::new(
+                self.system_program.clone(),
+                system_program::Transfer {
+                    from: self.authority.clone(),
+                    to: self.owner.clone(),
+                });
+
+            system_program::transfer(cpi_context, price)?;
+
+            Ok(())
+        }
+
+        /// Creates the metadata and master edition, and verifies the NFT as part
+        /// of the collection.
+        pub fn create_metadata(
+            &self,
+            item: &MintItem<'info>,
+            name: String,
+            symbol: String,
+            uri: String,
+        ) -> ProgramResult {
+            let creators = vec![
+                Creator {
+                    address: self.owner.key(),
+                    verified: true,
+                    share: 100,
+                },
+                // Add more creators if needed
+            ];
+
+            msg!(&self.owner.key().to_string());
+
+            invoke(
+                &mpl_token_metadata::instruction::create_metadata_accounts_v3(
+                    mpl_token_metadata::ID,
+                    item.metadata_account.key(),
+                    item.mint.key(),
+                    self.authority.key(),
+                    self.authority.key(),
+                    self.owner.key(),
+                    name,
+                    symbol,
+                    uri,
+                    Some(creators),
+                    500,
+                    true,
+                    false,
+                    None,
+                    None,
+                    None,
+                ),
+                &[
+                    item.metadata_account.clone(),
+                    item.mint.clone(),
+                    item.token_account.clone(),
+                    self.authority.clone(),
+                    self.rent.clone(),
+                    self.owner.clone(),
+                ],
+            )?;
+
+            msg!("Token mint process completed successfully.");
+
+            invoke(
+                &mpl_token_metadata::instruction::create_master_edition_v3(
+                    mpl_token_metadata::ID,
+                    item.edition.key(),
+                    item.mint.key(),
+                    self.owner.key(),
+                    self.authority.key(),
+                    item.metadata_account.key(),
+                    self.authority.key(),
+                    None,
+                ),
+                &[
+                    item.edition.clone(),
+                    item.metadata_account.clone(),
+                    item.mint.clone(),
+                    item.token_account.clone(),
+                    self.authority.clone(),
+                    self.rent.clone(),
+                    self.owner.clone(),
+                ],
+            )?;
+
+            invoke(
+                &mpl_token_metadata::instruction::set_and_verify_sized_collection_item(
+                    mpl_token_metadata::ID,
+                    item.metadata_account.key(),
+                    self.owner.key(),
+                    self.authority.key(),
+                    self.owner.key(),
+                    self.collection_mint.key(),
+                    self.collection.key(),
+                    self.collection_master_edition.key(),
+                    None,
+                ),
+                &[
+                    self.owner.clone(),
+                    self.authority.clone(),
+                    item.metadata_account.clone(),
+                    self.collection_mint.clone(),
+                    self.collection.clone(),
+                    self.collection_master_edition.clone(),
+                ],
+            )?;
+
+            Ok(())
+        }
+    }
+
+    /// Moves a weapon of this collection to `to`, applying its type's transfer
+    /// rules and charging the sender any transfer fee.
+    pub fn transfer(ctx: Context<Transfer>) -> ProgramResult {
+        let from = ctx.accounts.from.key();
+        let mint = ctx.accounts.mint.key();
+
+        ctx.accounts
+            .weapon_account
+            .check_holder(&ctx.accounts.from_association_token_account, &from)?;
+        ctx.accounts
+            .upgrade_weapon
+            .check_collection_member(&ctx.accounts.metadata_account, &mint)?;
+
+        let fee = ctx
+            .accounts
+            .token_type_config
+            .check_transfer(&ctx.accounts.weapon_account, Clock::get()?.unix_timestamp)?;
+
+        if fee > 0 {
+            system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.from.to_account_info(),
+                        to: ctx.accounts.owner.to_account_info(),
+                    },
+                ),
+                fee,
+            )?;
+        }
+
+        let cpi_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            anchor_spl::token::Transfer {
+                from: ctx
+                    .accounts
+                    .from_association_token_account
+                    .to_account_info(),
+                to: ctx.accounts.to_association_token_account.to_account_info(),
+                authority: ctx.accounts.from.to_account_info(),
+            },
+        );
+
+        anchor_spl::token::transfer(cpi_ctx, 1)?;
+
+        ctx.accounts.weapon_account.holder = ctx.accounts.to.key();
+
+        emit!(WeaponTransferred {
+            mint,
+            from,
+            to: ctx.accounts.to.key(),
+            fee,
+        });
+
+        Ok(())
+    }
+
+    pub fn burn(ctx: Context<Burn>) -> ProgramResult {
+        let token_account = &ctx.accounts.association_token_account;
+
+        if token_account.mint != ctx.accounts.mint.key()
+            || token_account.owner != ctx.accounts.authority.key()
+            || token_account.amount != 1
+        {
+            return Err(ErrorCode::InvalidTokenOwner.into());
+        }
+
+        ctx.accounts
+            .upgrade_weapon
+            .check_collection_member(&ctx.accounts.metadata_account, &ctx.accounts.mint.key())?;
+
+        // Socketed gems would be stuck in their vaults. Weapons on an older
+        // layout can't hold any.
+        let weapon_info = ctx.accounts.weapon_account.to_account_info();
+        if let Ok(weapon) = Weapon::try_deserialize(&mut &weapon_info.try_borrow_data()?[..]) {
+            if weapon.has_gems() {
+                return Err(ErrorCode::WeaponLocked.into());
+            }
+        }
+
+        msg!("Burning token");
+
+        invoke(
+            &mpl_token_metadata::instruction::burn_nft(
+                ctx.accounts.token_metadata_program.key(),
+                ctx.accounts.metadata_account.key(),
+                ctx.accounts.authority.key(),
+                ctx.accounts.mint.key(),
+                ctx.accounts.association_token_account.key(),
+                ctx.accounts.metadata_edition_account.key(),
+                ctx.accounts.token_program.key(),
+                None,
+            ),
+            &[
+                ctx.accounts.token_metadata_program.to_account_info(),
+                ctx.accounts.metadata_account.to_account_info(),
+                ctx.accounts.mint.to_account_info(),
+                ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.authority.to_account_info(),
+                ctx.accounts.association_token_account.to_account_info(),
+                ctx.accounts.metadata_edition_account.to_account_info(),
+            ],
+        )?;
+        msg!("Burning token completed");
+
+        let mint_record = &ctx.accounts.mint_record;
+        let token_type = mint_record.token_type;
+
+        ctx.accounts.upgrade_weapon.record_burn(token_type);
+
+        // Types can give the minter their per-wallet slot back, but only for
+        // weapons that used one in the first place. It is the minter's even
+        // when a later holder burns, see `TokenTypeConfig::burn_frees_wallet_slot`.
+        let used_wallet_slot = matches!(mint_record.source, MintSource::Mint | MintSource::Batch);
+        let counter_info = ctx.accounts.mint_counter.to_account_info();
+
+        if ctx.accounts.token_type_config.burn_frees_wallet_slot
+            && used_wallet_slot
+            && counter_info.owner == &crate::ID
+        {
+            let mut counter = MintCounter::try_deserialize(&mut &counter_info.try_borrow_data()?[..])?;
+            counter.minted = counter.minted.saturating_sub(1);
+            counter.store(&counter_info)?;
+        }
+
+        crate::close_pda_account(
+            &ctx.accounts.weapon_account.to_account_info(),
+            &ctx.accounts.authority.to_account_info(),
+        )?;
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct Transfer<'info> {
+        #[account(mut)]
+        pub from: Signer<'info>,
+
+        #[account(mut)]
+        pub from_association_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        #[account(mut)]
+        pub mint: Account<'info, anchor_spl::token::Mint>,
+
+        /// CHECK: any wallet; its token account is derived below
+        #[account(mut)]
+        pub to: UncheckedAccount<'info>,
+
+        #[account(
+            init_if_needed,
+            payer = from,
+            associated_token::mint = mint,
+            associated_token::authority = to
+        )]
+        pub to_association_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        /// CHECK: checked to be the mint's metadata, in our verified collection
+        pub metadata_account: UncheckedAccount<'info>,
+
+        #[account(mut, seeds = [b"weapon", mint.key().as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        #[account(seeds = [b"mintRecord", mint.key().as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        /// CHECK: the treasury, which collects transfer fees
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub owner: UncheckedAccount<'info>,
+
+        pub system_program: Program<'info, System>,
+        pub associated_token_program: Program<'info, anchor_spl::associated_token::AssociatedToken>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    #[derive(Accounts)]
+    #[instruction(name: String, symbol: String)]
+    pub struct Initialize<'info> {
+        #[account(init, payer = user, space = 1000 + size_of::<UpgradeWeapon>())]
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+        #[account(mut)]
+        /// CHECK: No checks through types are necessary for the `user` account.
+        pub user: Signer<'info>,
+        pub system_program: Program<'info, System>,
+    }
+
+    #[derive(Accounts)]
+    #[instruction(token_uri: String)]
+    pub struct AddTokenType<'info> {
+        #[account(mut)]
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+        #[account(signer)]
+        /// CHECK: No checks through types are necessary for the `user` account.
+        pub user: AccountInfo<'info>,
+    }
+
+    #[derive(Accounts)]
+    #[instruction(token_type: u64, token_uri: String)]
+    pub struct SetTokenTypeURI<'info> {
+        #[account(mut)]
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+        #[account(signer)]
+        /// CHECK: No checks through types are necessary for the `user` account.
+        pub user: AccountInfo<'info>,
+    }
+
+    #[derive(Accounts)]
+    #[instruction(token_type: u8)]
+    pub struct SetTokenTypeConfig<'info> {
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(
+            init_if_needed,
+            payer = authority,
+            space = 8 + TokenTypeConfig::INIT_SPACE,
+            seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[token_type]], bump
+        )]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+
+        pub system_program: Program<'info, System>,
+    }
+
+    #[derive(Accounts)]
+    #[instruction(token_id: u64)]
+    pub struct TokenURI<'info> {
+        #[account(mut)]
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+        #[account(signer)]
+        /// CHECK: No checks through types are necessary for the `user` account.
+        pub user: AccountInfo<'info>,
+        pub token_account: Account<'info, TokenAccount>,
+    }
+
+    #[derive(Accounts)]
+    pub struct Burn<'info> {
+        /// CHECK: checked to be the mint's metadata, in our verified collection
+        #[account(mut)]
+        metadata_account: UncheckedAccount<'info>,
+        #[account(mut)]
+        pub authority: Signer<'info>,
+        #[account(mut)]
+        pub mint: Account<'info, anchor_spl::token::Mint>,
+        #[account(mut)]
+        pub association_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        /// CHECK: We will create this outside
+        #[account(mut)]
+        pub metadata_edition_account: UncheckedAccount<'info>,
+
+        /// CHECK: We will create this outside
+        pub token_program: Program<'info, Token>,
+        /// CHECK: must be Metaplex, which does the burn
+        #[account(address = mpl_token_metadata::ID)]
+        pub token_metadata_program: UncheckedAccount<'info>,
+
+        #[account(mut)]
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        /// CHECK: closed by hand, since weapons minted with a record may still use
+        /// an older `Weapon` layout
+        #[account(mut, owner = crate::ID, seeds = [b"weapon", mint.key().as_ref()], bump)]
+        pub weapon_account: UncheckedAccount<'info>,
+
+        /// Weapons minted before records existed get theirs from `migrate_weapon`.
+        #[account(mut, close = authority, seeds = [b"mintRecord", mint.key().as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        /// CHECK: the minter's counter, which airdropped and redeemed weapons never created
+        #[account(
+            mut,
+            seeds = [
+                b"mintCounter",
+                upgrade_weapon.key().as_ref(),
+                mint_record.minter.as_ref(),
+                &[mint_record.token_type],
+            ],
+            bump
+        )]
+        pub mint_counter: UncheckedAccount<'info>,
+    }
+
+    #[account]
+    pub struct TokenAccount {
+        pub owner: Pubkey,
+        pub token_id: u64,
+    }
+
+    // Define errors
+
+    #[derive(Debug)]
+    pub enum ErrorCode {
+        InvalidTokenType,
+        InvalidTokenId,
+        InvalidTokenOwner,
+        InvalidBalance,
+        DuplicatedDevice,
+        InvalidSignature,
+        NftLimitExceeded,
+        WeaponNotMigrated,
+        SignatureExpired,
+        StaleNonce,
+        WalletLimitExceeded,
+        WindowLimitExceeded,
+        InvalidMintLimit,
+        InvalidMintPhase,
+        NoActivePhase,
+        NotAllowlisted,
+        PhaseAllocationExceeded,
+        InvalidMintWindow,
+        MintNotStarted,
+        MintEnded,
+        BatchTooLarge,
+        InvalidBatchAccounts,
+        FreeMintNotBatchable,
+        InvalidVoucher,
+        VoucherExpired,
+        NothingToRefund,
+        NotInCollection,
+        Soulbound,
+        TransferCooldown,
+        InvalidTransferRules,
+        InvalidPrice,
+        PriceChanged,
+        InvalidMarketConfig,
+        OfferExpired,
+        OfferNotExpired,
+        OfferMismatch,
+        InvalidRental,
+        RentalActive,
+        WeaponLocked,
+        InvalidSlot,
+        SlotOccupied,
+        InvalidUpgradeLimits,
+        UpgradeCooldown,
+        DailyUpgradeCapReached,
+        WeaponBroken,
+        InvalidPaymentAccount,
+        InvalidLevelTable,
+        XpCapExceeded,
+        StatsFromXpOnly,
+        InvalidRarityTiers,
+        NotAGem,
+        InvalidSocket,
+        NotAWeapon,
+        AlreadyMigrated,
+    }
+
+    impl From<ErrorCode> for ProgramError {
+        fn from(err: ErrorCode) -> ProgramError {
+            match err {
+                ErrorCode::InvalidTokenType => ProgramError::Custom(1),
+                ErrorCode::InvalidTokenId => ProgramError::Custom(2),
+                ErrorCode::InvalidTokenOwner => ProgramError::Custom(3),
+                ErrorCode::InvalidBalance => ProgramError::Custom(4),
+                ErrorCode::DuplicatedDevice => ProgramError::Custom(5),
+                ErrorCode::InvalidSignature => ProgramError::Custom(6),
+                ErrorCode::NftLimitExceeded => ProgramError::Custom(8),
+                ErrorCode::WeaponNotMigrated => ProgramError::Custom(9),
+                ErrorCode::SignatureExpired => ProgramError::Custom(10),
+                ErrorCode::StaleNonce => ProgramError::Custom(11),
+                ErrorCode::WalletLimitExceeded => ProgramError::Custom(12),
+                ErrorCode::WindowLimitExceeded => ProgramError::Custom(13),
+                ErrorCode::InvalidMintLimit => ProgramError::Custom(14),
+                ErrorCode::InvalidMintPhase => ProgramError::Custom(15),
+                ErrorCode::NoActivePhase => ProgramError::Custom(16),
+                ErrorCode::NotAllowlisted => ProgramError::Custom(17),
+                ErrorCode::PhaseAllocationExceeded => ProgramError::Custom(18),
+                ErrorCode::InvalidMintWindow => ProgramError::Custom(19),
+                ErrorCode::MintNotStarted => ProgramError::Custom(20),
+                ErrorCode::MintEnded => ProgramError::Custom(21),
+                ErrorCode::BatchTooLarge => ProgramError::Custom(22),
+                ErrorCode::InvalidBatchAccounts => ProgramError::Custom(23),
+                ErrorCode::FreeMintNotBatchable => ProgramError::Custom(24),
+                ErrorCode::InvalidVoucher => ProgramError::Custom(25),
+                ErrorCode::VoucherExpired => ProgramError::Custom(26),
+                ErrorCode::NothingToRefund => ProgramError::Custom(27),
+                ErrorCode::NotInCollection => ProgramError::Custom(28),
+                ErrorCode::Soulbound => ProgramError::Custom(29),
+                ErrorCode::TransferCooldown => ProgramError::Custom(30),
+                ErrorCode::InvalidTransferRules => ProgramError::Custom(31),
+                ErrorCode::InvalidPrice => ProgramError::Custom(32),
+                ErrorCode::PriceChanged => ProgramError::Custom(33),
+                ErrorCode::InvalidMarketConfig => ProgramError::Custom(34),
+                ErrorCode::OfferExpired => ProgramError::Custom(35),
+                ErrorCode::OfferNotExpired => ProgramError::Custom(36),
+                ErrorCode::OfferMismatch => ProgramError::Custom(37),
+                ErrorCode::InvalidRental => ProgramError::Custom(38),
+                ErrorCode::RentalActive => ProgramError::Custom(39),
+                ErrorCode::WeaponLocked => ProgramError::Custom(40),
+                ErrorCode::InvalidSlot => ProgramError::Custom(41),
+                ErrorCode::SlotOccupied => ProgramError::Custom(42),
+                ErrorCode::InvalidUpgradeLimits => ProgramError::Custom(43),
+                ErrorCode::UpgradeCooldown => ProgramError::Custom(44),
+                ErrorCode::DailyUpgradeCapReached => ProgramError::Custom(45),
+                ErrorCode::WeaponBroken => ProgramError::Custom(46),
+                ErrorCode::InvalidPaymentAccount => ProgramError::Custom(47),
+                ErrorCode::InvalidLevelTable => ProgramError::Custom(48),
+                ErrorCode::XpCapExceeded => ProgramError::Custom(49),
+                ErrorCode::StatsFromXpOnly => ProgramError::Custom(50),
+                ErrorCode::InvalidRarityTiers => ProgramError::Custom(51),
+                ErrorCode::NotAGem => ProgramError::Custom(52),
+                ErrorCode::InvalidSocket => ProgramError::Custom(53),
+                ErrorCode::NotAWeapon => ProgramError::Custom(54),
+                ErrorCode::AlreadyMigrated => ProgramError::Custom(55),
+            }
+        }
+    }
+
+    // Define events
+
+    #[event]
+    pub struct TokenURISuccess {
+        pub token_uri: String,
+    }
+
+    #[event]
+    pub struct WeaponTransferred {
+        pub mint: Pubkey,
+        pub from: Pubkey,
+        pub to: Pubkey,
+        pub fee: u64,
+    }
+
+    #[event]
+    pub struct WeaponListed {
+        pub mint: Pubkey,
+        pub seller: Pubkey,
+        pub price: u64,
+    }
+
+    #[event]
+    pub struct WeaponDelisted {
+        pub mint: Pubkey,
+        pub seller: Pubkey,
+    }
+
+    #[event]
+    pub struct WeaponSold {
+        pub mint: Pubkey,
+        pub seller: Pubkey,
+        pub buyer: Pubkey,
+        pub price: u64,
+        pub royalty: u64,
+    }
+
+    #[event]
+    pub struct OfferMade {
+        pub offer: Pubkey,
+        pub bidder: Pubkey,
+        pub target: OfferTarget,
+        pub price: u64,
+        pub expires_at: i64,
+    }
+
+    #[event]
+    pub struct OfferClosed {
+        pub offer: Pubkey,
+    }
+
+    #[event]
+    pub struct WeaponLent {
+        pub mint: Pubkey,
+        pub lender: Pubkey,
+        pub borrower: Pubkey,
+        pub fee: u64,
+        pub duration: i64,
+    }
+
+    #[event]
+    pub struct WeaponBorrowed {
+        pub mint: Pubkey,
+        pub borrower: Pubkey,
+        pub expires_at: i64,
+    }
+
+    #[event]
+    pub struct RentalEnded {
+        pub mint: Pubkey,
+    }
+
+    #[event]
+    pub struct WeaponStaked {
+        pub mint: Pubkey,
+        pub staker: Pubkey,
+    }
+
+    #[event]
+    pub struct RewardsClaimed {
+        pub mint: Pubkey,
+        pub staker: Pubkey,
+        pub amount: u64,
+    }
+
+    #[event]
+    pub struct WeaponUnstaked {
+        pub mint: Pubkey,
+        pub staker: Pubkey,
+        /// Rewards the vault couldn't pay out.
+        pub forfeited: u64,
+    }
+
+    #[event]
+    pub struct WeaponEquipped {
+        pub wallet: Pubkey,
+        pub mint: Pubkey,
+        pub slot: u8,
+    }
+
+    #[event]
+    pub struct WeaponUnequipped {
+        pub wallet: Pubkey,
+        pub mint: Pubkey,
+        pub slot: u8,
+    }
+
+    #[event]
+    pub struct CharacterStatsComputed {
+        pub wallet: Pubkey,
+        pub level: u64,
+        pub hp: u64,
+        pub damage: u64,
+        pub mana: u64,
+        pub mp_regen: u64,
+        pub atk_speed: u64,
+    }
+
+    #[event]
+    pub struct DurabilityChanged {
+        pub mint: Pubkey,
+        pub durability: u64,
+        pub broken: bool,
+    }
+
+    #[event]
+    pub struct XpGranted {
+        pub mint: Pubkey,
+        pub amount: u64,
+        pub xp: u64,
+        pub level: u64,
+        pub levels_gained: u64,
+    }
+
+    #[event]
+    pub struct GemSocketed {
+        pub mint: Pubkey,
+        pub gem: Pubkey,
+        pub socket: u8,
+    }
+
+    #[event]
+    pub struct GemUnsocketed {
+        pub mint: Pubkey,
+        pub gem: Pubkey,
+        pub socket: u8,
+    }
+
+    #[event]
+    pub struct MintRefunded {
+        pub mint: Pubkey,
+        pub minter: Pubkey,
+        pub amount: u64,
+    }
+
+    // Define additional structs
+
+    #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+    pub struct TokenType {
+        pub id: u64,
+        pub token_type: u64,
+    }
+
+    #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+    pub struct TokenDevice {
+        pub token_type: u8,
+        pub device_id: String,
+        /// Last second the backend's attestation can be used, so a leaked
+        /// signature can't be replayed later.
+        pub expires_at: i64,
+    }
+
+    impl TokenDevice {
+        /// Seed of the device's `DeviceAccount`, so raw ids never end up on chain
+        /// as account addresses.
+        pub fn hash(&self) -> [u8; 32] {
+            solana_program::hash::hash(self.device_id.as_bytes()).to_bytes()
+        }
+
+        /// Bytes our backend signs to attest that `wallet` mints from this device
+        /// before `expires_at`.
+        pub fn attestation_message(&self, wallet: &Pubkey) -> Vec<u8> {
+            let mut message = wallet.to_bytes().to_vec();
+            message.push(self.token_type);
+            message.extend_from_slice(&self.hash());
+            message.extend_from_slice(&self.expires_at.to_le_bytes());
+            message
+        }
+    }
+
+    #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+    pub struct TokenTypeURI {
+        pub id: u64,
+        pub token_uri: String,
+        pub name: String,
+    }
+
+    /// Layout version written by the current program. Bump it whenever a field
+    /// is appended to `Weapon` and teach `migrate_weapon` how to fill it in.
+    pub const WEAPON_VERSION: u8 = 11;
+
+    // New fields must only ever be appended, and must read as a sensible
+    // default from zeroed bytes, so that `migrate_weapon` can grow old accounts
+    // in place. Accounts written before versioning have `version == 0`.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct Weapon {
+        level: u64,
+        hp: u64,
+        damage: u64,
+        mana: u64,
+        mp_regen: u64,
+        atk_speed: u64,
+        version: u8,
+        mint: Pubkey,
+        stat_nonce: u64,
+        /// Wallet the NFT was last minted or transferred to through this program.
+        /// Migrated weapons start with the default key until their next transfer.
+        holder: Pubkey,
+        last_upgraded_at: i64,
+        /// Profile the weapon is equipped on, the default key when it isn't.
+        equipped_to: Pubkey,
+        /// Durability lost since the last repair. Stored as wear rather than
+        /// durability so that zeroed, migrated weapons start out intact.
+        wear: u64,
+        broken: bool,
+        xp: u64,
+        /// UTC day of the last XP grant and the XP granted on it, for the
+        /// type's daily cap.
+        xp_day: i64,
+        xp_today: u64,
+        /// Index into `RARITY_NAMES`, rolled at mint. Common for weapons of types
+        /// without rarity tiers and for weapons minted before tiers existed.
+        rarity: u8,
+        /// Hp, damage, mana, mp regen and attack speed as upgrades and level-ups
+        /// set them, before the rarity bonus. The stats above derive from these.
+        base_stats: [u64; 5],
+        /// Mints of the gems in each socket, the default key for an empty one.
+        gems: [Pubkey; MAX_SOCKETS],
+        /// Stats each socketed gem added when it went in, so taking it out
+        /// removes exactly that even if its type's bonus changed since.
+        gem_bonuses: [[u64; 5]; MAX_SOCKETS],
+    }
+
+    /// Most sockets any weapon can have.
+    pub const MAX_SOCKETS: usize = 3;
+
+    /// Durability of an unworn weapon.
+    pub const MAX_DURABILITY: u64 = 100;
+
+    impl Weapon {
+        /// A fresh level 0 weapon for the NFT `mint`, held by `holder`.
+        pub fn new(mint: Pubkey, holder: Pubkey, rarity: u8) -> Self {
+            Weapon {
+                level: 0,
+                hp: 0,
+                damage: 0,
+                mana: 0,
+                mp_regen: 0,
+                atk_speed: 0,
+                version: WEAPON_VERSION,
+                mint,
+                stat_nonce: 0,
+                holder,
+                last_upgraded_at: 0,
+                equipped_to: Pubkey::default(),
+                wear: 0,
+                broken: false,
+                xp: 0,
+                xp_day: 0,
+                xp_today: 0,
+                rarity,
+                base_stats: [0; 5],
+                gems: [Pubkey::default(); MAX_SOCKETS],
+                gem_bonuses: [[0; 5]; MAX_SOCKETS],
+            }
+        }
+
+        /// Sets the weapon's base stats and derives the stats the game reads
+        /// from them. Every stat change goes through here, so the rarity bonus
+        /// applies to all of them the same way.
+        fn set_base_stats(&mut self, config: &TokenTypeConfig, base_stats: [u64; 5]) {
+            self.base_stats = base_stats;
+            self.refresh_stats(config);
+        }
+
+        /// Derives the stats the game reads: base stats with the rarity bonus,
+        /// plus the bonuses of socketed gems.
+        fn refresh_stats(&mut self, config: &TokenTypeConfig) {
+            let mut stats = self.base_stats.map(|stat| config.with_rarity_bonus(self.rarity, stat));
+
+            for bonus in &self.gem_bonuses {
+                for (stat, bonus) in stats.iter_mut().zip(bonus) {
+                    *stat = stat.saturating_add(*bonus);
+                }
+            }
+
+            let [hp, damage, mana, mp_regen, atk_speed] = stats;
+            self.hp = hp;
+            self.damage = damage;
+            self.mana = mana;
+            self.mp_regen = mp_regen;
+            self.atk_speed = atk_speed;
+        }
+
+        /// Fails unless `holder` owns `token_account`, or is an approved delegate
+        /// on it, and the account holds this weapon's NFT.
+        pub fn check_holder(
+            &self,
+            token_account: &anchor_spl::token::TokenAccount,
+            holder: &Pubkey,
+        ) -> ProgramResult {
+            if self.mint == Pubkey::default() {
+                return Err(ErrorCode::WeaponNotMigrated.into());
+            }
+
+            if token_account.mint != self.mint || token_account.amount != 1 {
+                return Err(ErrorCode::InvalidTokenOwner.into());
+            }
+
+            let is_delegate = token_account.delegate == COption::Some(*holder)
+                && token_account.delegated_amount >= 1;
+
+            if token_account.owner != *holder && !is_delegate {
+                return Err(ErrorCode::InvalidTokenOwner.into());
+            }
+
+            Ok(())
+        }
+
+        pub fn durability(&self) -> u64 {
+            MAX_DURABILITY.saturating_sub(self.wear)
+        }
+
+        /// Fails for a broken weapon, which can't be upgraded or listed.
+        pub fn check_not_broken(&self) -> ProgramResult {
+            if self.broken {
+                return Err(ErrorCode::WeaponBroken.into());
+            }
+
+            Ok(())
+        }
+
+        pub fn has_gems(&self) -> bool {
+            self.gems.iter().any(|gem| *gem != Pubkey::default())
+        }
+
+        /// Level, hp, damage, mana, mp regen and attack speed, in that order.
+        pub fn stats(&self) -> [u64; 6] {
+            [self.level, self.hp, self.damage, self.mana, self.mp_regen, self.atk_speed]
+        }
+
+        /// Bytes the game server signs to authorize `upgrade_weapon_level_signed`.
+        pub fn signed_upgrade_message(
+            weapon: &Pubkey,
+            level: &[u64; 6],
+            nonce: u64,
+            expires_at: i64,
+        ) -> Vec<u8> {
+            let mut message = weapon.to_bytes().to_vec();
+            for stat in level {
+                message.extend_from_slice(&stat.to_le_bytes());
+            }
+            message.extend_from_slice(&nonce.to_le_bytes());
+            message.extend_from_slice(&expires_at.to_le_bytes());
+            message
+        }
+    }
+
+    #[derive(Accounts)]
+    pub struct UpgradeWeaponLevel<'info> {
+        #[account(mut)]
+        pub owner: Signer<'info>,
+
+        #[account(mut, seeds = [b"weapon", weapon_account.mint.as_ref()], bump)]
+        pub weapon_account: Account<'info, Weapon>,
+
+        pub token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(seeds = [b"mintRecord", weapon_account.mint.as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        #[account(
+            init_if_needed,
+            payer = authority,
+            space = 8 + UpgradeCounter::INIT_SPACE,
+            seeds = [
+                b"upgradeCounter",
+                upgrade_weapon.key().as_ref(),
+                owner.key().as_ref(),
+                &[mint_record.token_type],
+            ],
+            bump
+        )]
+        pub upgrade_counter: Box<Account<'info, UpgradeCounter>>,
+
+        pub system_program: Program<'info, System>,
+    }
+
+    pub fn upgrade_weapon_level(
+        ctx: Context<UpgradeWeaponLevel>,
+        level: [u64; 6],
+    ) -> ProgramResult {
+        let metadata_acc: &mut Account<'_, Weapon> = &mut ctx.accounts.weapon_account;
+
+        metadata_acc.check_holder(&ctx.accounts.token_account, &ctx.accounts.owner.key())?;
+        metadata_acc.check_not_broken()?;
+
+        let now = Clock::get()?.unix_timestamp;
+        let config = &ctx.accounts.token_type_config;
+        config.check_direct_upgrade()?;
+        config.record_upgrade(metadata_acc, &mut ctx.accounts.upgrade_counter, now)?;
+
+        metadata_acc.last_upgraded_at = now;
+        metadata_acc.level = level[0];
+        metadata_acc.set_base_stats(config, [level[1], level[2], level[3], level[4], level[5]]);
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    #[instruction(token_type: u8)]
+    pub struct MigrateWeapon<'info> {
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        /// CHECK: may still use an older `Weapon` layout, so it is resized before
+        /// being deserialized by hand, which also checks the discriminator
+        #[account(mut, owner = crate::ID, seeds = [b"weapon", mint.key().as_ref()], bump)]
+        pub weapon_account: UncheckedAccount<'info>,
+
+        /// CHECK: only used to derive the weapon PDA and backfill `Weapon::mint`
+        pub mint: UncheckedAccount<'info>,
+
+        /// CHECK: created by hand for weapons minted before mint records existed,
+        /// left alone otherwise
+        #[account(mut, seeds = [b"mintRecord", mint.key().as_ref()], bump)]
+        pub mint_record: UncheckedAccount<'info>,
+
+        pub system_program: Program<'info, System>,
+    }
+
+    #[derive(Accounts)]
+    #[instruction(token_type: u8)]
+    pub struct MigrateTokenTypeConfig<'info> {
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        /// CHECK: may still use an older `TokenTypeConfig` layout, so it is resized
+        /// before being deserialized by hand, which also checks the discriminator
+        #[account(
+            mut,
+            owner = crate::ID,
+            seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[token_type]], bump
+        )]
+        pub token_type_config: UncheckedAccount<'info>,
+
+        pub system_program: Program<'info, System>,
+    }
+
+    /// Grows a `TokenTypeConfig` created before its latest fields were added.
+    pub fn migrate_token_type_config(ctx: Context<MigrateTokenTypeConfig>, _token_type: u8) -> ProgramResult {
+        let config_info = ctx.accounts.token_type_config.to_account_info();
+
+        crate::grow_account(
+            &config_info,
+            &ctx.accounts.authority,
+            &ctx.accounts.system_program,
+            8 + TokenTypeConfig::INIT_SPACE,
+        )?;
+
+        TokenTypeConfig::try_deserialize(&mut &config_info.try_borrow_data()?[..])?;
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct MigrateLiveSupply<'info> {
+        #[account(mut)]
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+    }
+
+    /// Seeds `live_supply` on deployments that minted before it was tracked,
+    /// where it reads 0 from the account's zeroed tail. `burned` is how many
+    /// weapons of each type were burned so far, which `token_counter` never
+    /// took off. Run it right after upgrading the program: it can only run
+    /// once, since later mints and burns would make `burned` stale.
+    pub fn migrate_live_supply(ctx: Context<MigrateLiveSupply>, burned: [u64; 5]) -> ProgramResult {
+        let upgrade_weapon = &mut ctx.accounts.upgrade_weapon;
+
+        if upgrade_weapon.live_supply_migrated {
+            return Err(ErrorCode::AlreadyMigrated.into());
+        }
+        upgrade_weapon.live_supply_migrated = true;
+
+        for (token_type, burned) in burned.iter().enumerate() {
+            upgrade_weapon.live_supply[token_type] = upgrade_weapon.token_counter[token_type].saturating_sub(*burned);
+        }
+
+        Ok(())
+    }
+
+    /// Brings a weapon to the current layout. Weapons minted before mint records
+    /// existed also get one, of `token_type` as the admin knows it from the NFT's
+    /// metadata, since every instruction keyed on the weapon's type needs it.
+    /// `token_type` is ignored when the record is already there.
+    pub fn migrate_weapon(ctx: Context<MigrateWeapon>, token_type: u8) -> ProgramResult {
+        let weapon_info = ctx.accounts.weapon_account.to_account_info();
+
+        crate::grow_account(
+            &weapon_info,
+            &ctx.accounts.authority,
+            &ctx.accounts.system_program,
+            8 + Weapon::INIT_SPACE,
+        )?;
+
+        let mut weapon = Weapon::try_deserialize(&mut &weapon_info.try_borrow_data()?[..])?;
+
+        msg!(&format!("Migrating weapon from version {} to {}", weapon.version, WEAPON_VERSION));
+
+        if weapon.version < 2 {
+            weapon.mint = ctx.accounts.mint.key();
+        }
+
+        // Stats used to be stored with level-up rarity bonuses already in,
+        // which can't be told apart anymore, so they become the base as is.
+        if weapon.version < 9 {
+            weapon.base_stats = [weapon.hp, weapon.damage, weapon.mana, weapon.mp_regen, weapon.atk_speed];
+        }
+
+        weapon.version = WEAPON_VERSION;
+        weapon.try_serialize(&mut &mut weapon_info.try_borrow_mut_data()?[..])?;
+
+        let record_info = ctx.accounts.mint_record.to_account_info();
+
+        if record_info.owner == &system_program::ID {
+            ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
+
+            crate::create_pda_account(
+                &record_info,
+                &ctx.accounts.authority.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+                &[b"mintRecord", ctx.accounts.mint.key.as_ref()],
+                8 + MintRecord::INIT_SPACE,
+            )?;
+            MintRecord {
+                token_type,
+                source: MintSource::Legacy,
+                minter: Pubkey::default(),
+                price: 0,
+                minted_at: 0,
+            }
+            .try_serialize(&mut &mut record_info.try_borrow_mut_data()?[..])?;
+        }
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct UpgradeWeaponLevelSigned<'info> {
+        #[account(mut)]
+        pub owner: Signer<'info>,
+
+        #[account(mut, seeds = [b"weapon", weapon_account.mint.as_ref()], bump)]
+        pub weapon_account: Account<'info, Weapon>,
+
+        pub token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        /// CHECK: the instructions sysvar, used to find the Ed25519 verification
+        #[account(address = solana_program::sysvar::instructions::ID)]
+        pub instructions: UncheckedAccount<'info>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(seeds = [b"mintRecord", weapon_account.mint.as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        #[account(
+            init_if_needed,
+            payer = owner,
+            space = 8 + UpgradeCounter::INIT_SPACE,
+            seeds = [
+                b"upgradeCounter",
+                upgrade_weapon.key().as_ref(),
+                owner.key().as_ref(),
+                &[mint_record.token_type],
+            ],
+            bump
+        )]
+        pub upgrade_counter: Box<Account<'info, UpgradeCounter>>,
+
+        pub system_program: Program<'info, System>,
+    }
+
+    /// Same as `upgrade_weapon_level`, but submitted and paid for by the player.
+    /// The game server's approval comes from an Ed25519 program instruction placed
+    /// anywhere earlier in the transaction, signing `Weapon::signed_upgrade_message`.
+    pub fn upgrade_weapon_level_signed(
+        ctx: Context<UpgradeWeaponLevelSigned>,
+        level: [u64; 6],
+        nonce: u64,
+        expires_at: i64,
+    ) -> ProgramResult {
+        let weapon_key = ctx.accounts.weapon_account.key();
+        let metadata_acc: &mut Account<'_, Weapon> = &mut ctx.accounts.weapon_account;
+
+        metadata_acc.check_holder(&ctx.accounts.token_account, &ctx.accounts.owner.key())?;
+        metadata_acc.check_not_broken()?;
+
+        let now = Clock::get()?.unix_timestamp;
+
+        if now > expires_at {
+            return Err(ErrorCode::SignatureExpired.into());
+        }
+
+        if nonce <= metadata_acc.stat_nonce {
+            return Err(ErrorCode::StaleNonce.into());
+        }
+
+        crate::verify_ed25519_instruction(
+            &ctx.accounts.instructions,
+            &solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"),
+            &Weapon::signed_upgrade_message(&weapon_key, &level, nonce, expires_at),
+        )?;
+
+        let config = &ctx.accounts.token_type_config;
+        config.check_direct_upgrade()?;
+        config.record_upgrade(metadata_acc, &mut ctx.accounts.upgrade_counter, now)?;
+
+        metadata_acc.stat_nonce = nonce;
+        metadata_acc.last_upgraded_at = now;
+        metadata_acc.level = level[0];
+        metadata_acc.set_base_stats(config, [level[1], level[2], level[3], level[4], level[5]]);
+
+        Ok(())
+    }
+
+    /// Trade settings of an `UpgradeWeapon`'s collection.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct MarketConfig {
+        /// Share of each sale paid to `royalty_recipient`, in basis points.
+        pub royalty_bps: u16,
+        pub royalty_recipient: Pubkey,
+        /// Share of each sale paid to the treasury, in basis points.
+        pub protocol_fee_bps: u16,
+    }
+
+    impl MarketConfig {
+        /// Splits a sale at `price` into the royalty, the treasury's cut (protocol
+        /// fee plus the type's `transfer_fee`) and what is left for the seller.
+        pub fn split(&self, price: u64, transfer_fee: u64) -> std::result::Result<(u64, u64, u64), ProgramError> {
+            let share = |bps: u16| (price as u128 * bps as u128 / 10_000) as u64;
+
+            let royalty = share(self.royalty_bps);
+            let treasury = share(self.protocol_fee_bps)
+                .checked_add(transfer_fee)
+                .ok_or(ErrorCode::InvalidPrice)?;
+            let seller = price
+                .checked_sub(royalty)
+                .and_then(|rest| rest.checked_sub(treasury))
+                .ok_or(ErrorCode::InvalidPrice)?;
+
+            Ok((royalty, treasury, seller))
+        }
+    }
+
+    /// A weapon for sale. The NFT sits in the `escrow` token account, owned by
+    /// this listing, until it is bought or delisted.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct Listing {
+        pub seller: Pubkey,
+        pub upgrade_weapon: Pubkey,
+        /// Lamports the buyer pays.
+        pub price: u64,
+        pub listed_at: i64,
+    }
+
+    #[derive(Accounts)]
+    pub struct SetMarketConfig<'info> {
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(
+            init_if_needed,
+            payer = authority,
+            space = 8 + MarketConfig::INIT_SPACE,
+            seeds = [b"market", upgrade_weapon.key().as_ref()], bump
+        )]
+        pub market_config: Box<Account<'info, MarketConfig>>,
+
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+
+        pub system_program: Program<'info, System>,
+    }
+
+    pub fn set_market_config(
+        ctx: Context<SetMarketConfig>,
+        royalty_bps: u16,
+        royalty_recipient: Pubkey,
+        protocol_fee_bps: u16,
+    ) -> ProgramResult {
+        if royalty_bps as u32 + protocol_fee_bps as u32 > 10_000 {
+            return Err(ErrorCode::InvalidMarketConfig.into());
+        }
+
+        ctx.accounts.market_config.set_inner(MarketConfig {
+            royalty_bps,
+            royalty_recipient,
+            protocol_fee_bps,
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct List<'info> {
+        #[account(mut)]
+        pub seller: Signer<'info>,
+
+        pub mint: Account<'info, anchor_spl::token::Mint>,
+
+        #[account(mut)]
+        pub seller_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        #[account(
+            init,
+            payer = seller,
+            space = 8 + Listing::INIT_SPACE,
+            seeds = [b"listing", mint.key().as_ref()], bump
+        )]
+        pub listing: Box<Account<'info, Listing>>,
+
+        #[account(
+            init,
+            payer = seller,
+            token::mint = mint,
+            token::authority = listing,
+            seeds = [b"escrow", mint.key().as_ref()], bump
+        )]
+        pub escrow: Box<Account<'info, anchor_spl::token::TokenAccount>>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        /// CHECK: checked to be the mint's metadata, in our verified collection
+        pub metadata_account: UncheckedAccount<'info>,
+
+        #[account(seeds = [b"weapon", mint.key().as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        #[account(seeds = [b"mintRecord", mint.key().as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        pub rent: Sysvar<'info, Rent>,
+        pub system_program: Program<'info, System>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    /// Puts a weapon up for sale at `price` lamports, moving the NFT into escrow.
+    /// Types that `transfer` refuses can't be listed either.
+    pub fn list(ctx: Context<List>, price: u64) -> ProgramResult {
+        let seller = ctx.accounts.seller.key();
+        let mint = ctx.accounts.mint.key();
+
+        if price == 0 {
+            return Err(ErrorCode::InvalidPrice.into());
+        }
+
+        ctx.accounts
+            .weapon_account
+            .check_holder(&ctx.accounts.seller_token_account, &seller)?;
+        ctx.accounts.weapon_account.check_not_broken()?;
+        ctx.accounts
+            .upgrade_weapon
+            .check_collection_member(&ctx.accounts.metadata_account, &mint)?;
+
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts
+            .token_type_config
+            .check_transfer(&ctx.accounts.weapon_account, now)?;
+
+        anchor_spl::token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: ctx.accounts.seller_token_account.to_account_info(),
+                    to: ctx.accounts.escrow.to_account_info(),
+                    authority: ctx.accounts.seller.to_account_info(),
+                },
+            ),
+            1,
+        )?;
+
+        ctx.accounts.listing.set_inner(Listing {
+            seller,
+            upgrade_weapon: ctx.accounts.upgrade_weapon.key(),
+            price,
+            listed_at: now,
+        });
+
+        emit!(WeaponListed { mint, seller, price });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct UpdatePrice<'info> {
+        pub seller: Signer<'info>,
+
+        /// CHECK: only used to find the listing
+        pub mint: UncheckedAccount<'info>,
+
+        #[account(mut, has_one = seller, seeds = [b"listing", mint.key().as_ref()], bump)]
+        pub listing: Box<Account<'info, Listing>>,
+    }
+
+    pub fn update_price(ctx: Context<UpdatePrice>, price: u64) -> ProgramResult {
+        if price == 0 {
+            return Err(ErrorCode::InvalidPrice.into());
+        }
+
+        ctx.accounts.listing.price = price;
+
+        emit!(WeaponListed {
+            mint: ctx.accounts.mint.key(),
+            seller: ctx.accounts.seller.key(),
+            price,
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct Delist<'info> {
+        #[account(mut)]
+        pub seller: Signer<'info>,
+
+        pub mint: Account<'info, anchor_spl::token::Mint>,
+
+        #[account(
+            init_if_needed,
+            payer = seller,
+            associated_token::mint = mint,
+            associated_token::authority = seller
+        )]
+        pub seller_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        #[account(mut, has_one = seller, close = seller, seeds = [b"listing", mint.key().as_ref()], bump)]
+        pub listing: Box<Account<'info, Listing>>,
+
+        #[account(mut, seeds = [b"escrow", mint.key().as_ref()], bump)]
+        pub escrow: Box<Account<'info, anchor_spl::token::TokenAccount>>,
+
+        pub system_program: Program<'info, System>,
+        pub associated_token_program: Program<'info, anchor_spl::associated_token::AssociatedToken>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    /// Takes a weapon off sale and gives it back to the seller.
+    pub fn delist(ctx: Context<Delist>) -> ProgramResult {
+        crate::release_escrow(
+            &ctx.accounts.escrow.to_account_info(),
+            &ctx.accounts.seller_token_account.to_account_info(),
+            &ctx.accounts.listing.to_account_info(),
+            &ctx.accounts.seller,
+            &ctx.accounts.token_program,
+            &ctx.accounts.mint.key(),
+            *ctx.bumps.get("listing").unwrap(),
+        )?;
+
+        emit!(WeaponDelisted {
+            mint: ctx.accounts.mint.key(),
+            seller: ctx.accounts.seller.key(),
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct Buy<'info> {
+        #[account(mut)]
+        pub buyer: Signer<'info>,
+
+        /// CHECK: the listing's seller, who is paid and gets the rent back
+        #[account(mut)]
+        pub seller: UncheckedAccount<'info>,
+
+        pub mint: Account<'info, anchor_spl::token::Mint>,
+
+        #[account(
+            init_if_needed,
+            payer = buyer,
+            associated_token::mint = mint,
+            associated_token::authority = buyer
+        )]
+        pub buyer_token_account: Box<Account<'info, anchor_spl::token::TokenAccount>>,
+
+        #[account(
+            mut,
+            has_one = seller,
+            has_one = upgrade_weapon,
+            close = seller,
+            seeds = [b"listing", mint.key().as_ref()], bump
+        )]
+        pub listing: Box<Account<'info, Listing>>,
+
+        #[account(mut, seeds = [b"escrow", mint.key().as_ref()], bump)]
+        pub escrow: Box<Account<'info, anchor_spl::token::TokenAccount>>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(mut, seeds = [b"weapon", mint.key().as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        #[account(seeds = [b"mintRecord", mint.key().as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        #[account(seeds = [b"market", upgrade_weapon.key().as_ref()], bump)]
+        pub market_config: Box<Account<'info, MarketConfig>>,
+
+        /// CHECK: receives the royalty
+        #[account(mut, address = market_config.royalty_recipient)]
+        pub royalty_recipient: UncheckedAccount<'info>,
+
+        /// CHECK: the treasury, which collects protocol and transfer fees
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub owner: UncheckedAccount<'info>,
+
+        pub system_program: Program<'info, System>,
+        pub associated_token_program: Program<'info, anchor_spl::associated_token::AssociatedToken>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    /// Buys a listed weapon. `price` must match the listing, so a seller can't
+    /// raise it between the buyer signing and the transaction landing. The
+    /// type's transfer fee comes out of the seller's share, as with `transfer`.
+    pub fn buy(ctx: Context<Buy>, price: u64) -> ProgramResult {
+        let listing = &ctx.accounts.listing;
+
+        if listing.price != price {
+            return Err(ErrorCode::PriceChanged.into());
+        }
+
+        // Wear can still break a weapon while it is listed.
+        ctx.accounts.weapon_account.check_not_broken()?;
+
+        let transfer_fee = ctx
+            .accounts
+            .token_type_config
+            .check_transfer(&ctx.accounts.weapon_account, Clock::get()?.unix_timestamp)?;
+        let (royalty, treasury, proceeds) = ctx.accounts.market_config.split(price, transfer_fee)?;
+
+        let buyer = ctx.accounts.buyer.to_account_info();
+        let system_program = ctx.accounts.system_program.to_account_info();
+
+        crate::transfer_lamports(&system_program, &buyer, &ctx.accounts.royalty_recipient, royalty)?;
+        crate::transfer_lamports(&system_program, &buyer, &ctx.accounts.owner, treasury)?;
+        crate::transfer_lamports(&system_program, &buyer, &ctx.accounts.seller, proceeds)?;
+
+        crate::release_escrow(
+            &ctx.accounts.escrow.to_account_info(),
+            &ctx.accounts.buyer_token_account.to_account_info(),
+            &ctx.accounts.listing.to_account_info(),
+            &ctx.accounts.seller,
+            &ctx.accounts.token_program,
+            &ctx.accounts.mint.key(),
+            *ctx.bumps.get("listing").unwrap(),
+        )?;
+
+        ctx.accounts.weapon_account.holder = ctx.accounts.buyer.key();
+
+        let mint = ctx.accounts.mint.key();
+        let seller = ctx.accounts.seller.key();
+        let buyer = ctx.accounts.buyer.key();
+
+        emit!(WeaponTransferred {
+            mint,
+            from: seller,
+            to: buyer,
+            fee: transfer_fee,
+        });
+        emit!(WeaponSold {
+            mint,
+            seller,
+            buyer,
+            price,
+            royalty,
+        });
+
+        Ok(())
+    }
+
+    #[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, InitSpace)]
+    pub enum OfferTarget {
+        /// One specific weapon.
+        Mint { mint: Pubkey },
+        /// Any weapon of `token_type` at `min_level` or above, i.e. a floor bid.
+        TokenType { token_type: u8, min_level: u64 },
+    }
+
+    /// SOL a player put up for a weapon. The offer account itself holds `price`
+    /// lamports on top of its rent until it is accepted or closed.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct Offer {
+        pub bidder: Pubkey,
+        pub upgrade_weapon: Pubkey,
+        pub target: OfferTarget,
+        pub price: u64,
+        pub expires_at: i64,
+    }
+
+    impl Offer {
+        /// Fails unless the weapon `mint` of `token_type` matches the offer.
+        pub fn check_target(&self, mint: &Pubkey, token_type: u8, weapon: &Weapon) -> ProgramResult {
+            let matches = match self.target {
+                OfferTarget::Mint { mint: wanted } => wanted == *mint,
+                OfferTarget::TokenType {
+                    token_type: wanted,
+                    min_level,
+                } => wanted == token_type && weapon.level >= min_level,
+            };
+
+            if !matches {
+                return Err(ErrorCode::OfferMismatch.into());
+            }
+
+            Ok(())
+        }
+    }
+
+    #[derive(Accounts)]
+    #[instruction(id: u64)]
+    pub struct MakeOffer<'info> {
+        #[account(mut)]
+        pub bidder: Signer<'info>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(
+            init,
+            payer = bidder,
+            space = 8 + Offer::INIT_SPACE,
+            seeds = [b"offer", upgrade_weapon.key().as_ref(), bidder.key().as_ref(), &id.to_le_bytes()], bump
+        )]
+        pub offer: Box<Account<'info, Offer>>,
+
+        pub system_program: Program<'info, System>,
+    }
+
+    /// Escrows `price` lamports for a weapon matching `target` until `expires_at`.
+    /// `id` tells apart a bidder's offers.
+    pub fn make_offer(
+        ctx: Context<MakeOffer>,
+        _id: u64,
+        target: OfferTarget,
+        price: u64,
+        expires_at: i64,
+    ) -> ProgramResult {
+        if price == 0 {
+            return Err(ErrorCode::InvalidPrice.into());
+        }
+
+        if expires_at <= Clock::get()?.unix_timestamp {
+            return Err(ErrorCode::OfferExpired.into());
+        }
+
+        if let OfferTarget::TokenType { token_type, .. } = target {
+            ctx.accounts.upgrade_weapon.check_token_type(token_type)?;
+        }
+
+        crate::transfer_lamports(
+            &ctx.accounts.system_program.to_account_info(),
+            &ctx.accounts.bidder.to_account_info(),
+            &ctx.accounts.offer.to_account_info(),
+            price,
+        )?;
+
+        ctx.accounts.offer.set_inner(Offer {
+            bidder: ctx.accounts.bidder.key(),
+            upgrade_weapon: ctx.accounts.upgrade_weapon.key(),
+            target: target.clone(),
+            price,
+            expires_at,
+        });
+
+        emit!(OfferMade {
+            offer: ctx.accounts.offer.key(),
+            bidder: ctx.accounts.bidder.key(),
+            target,
+            price,
+            expires_at,
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct CloseOffer<'info> {
+        pub authority: Signer<'info>,
+
+        /// CHECK: the offer's bidder, who gets the escrow and rent back
+        #[account(mut)]
+        pub bidder: UncheckedAccount<'info>,
+
+        #[account(mut, has_one = bidder, close = bidder)]
+        pub offer: Box<Account<'info, Offer>>,
+    }
+
+    /// Refunds an offer. The bidder can withdraw it at any time, and anyone can
+    /// hand an expired offer back to its bidder.
+    pub fn close_offer(ctx: Context<CloseOffer>) -> ProgramResult {
+        let expired = Clock::get()?.unix_timestamp >= ctx.accounts.offer.expires_at;
+
+        if ctx.accounts.authority.key() != ctx.accounts.bidder.key() && !expired {
+            return Err(ErrorCode::OfferNotExpired.into());
+        }
+
+        emit!(OfferClosed {
+            offer: ctx.accounts.offer.key(),
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct AcceptOffer<'info> {
+        #[account(mut)]
+        pub seller: Signer<'info>,
+
+        #[account(mut)]
+        pub seller_token_account: Box<Account<'info, anchor_spl::token::TokenAccount>>,
+
+        /// CHECK: the offer's bidder, who gets the NFT and the offer's rent
+        #[account(mut)]
+        pub bidder: UncheckedAccount<'info>,
+
+        #[account(
+            init_if_needed,
+            payer = seller,
+            associated_token::mint = mint,
+            associated_token::authority = bidder
+        )]
+        pub bidder_token_account: Box<Account<'info, anchor_spl::token::TokenAccount>>,
+
+        #[account(mut, has_one = bidder, has_one = upgrade_weapon, close = bidder)]
+        pub offer: Box<Account<'info, Offer>>,
+
+        pub mint: Account<'info, anchor_spl::token::Mint>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        /// CHECK: checked to be the mint's metadata, in our verified collection
+        pub metadata_account: UncheckedAccount<'info>,
+
+        #[account(mut, seeds = [b"weapon", mint.key().as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        #[account(seeds = [b"mintRecord", mint.key().as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        #[account(seeds = [b"market", upgrade_weapon.key().as_ref()], bump)]
+        pub market_config: Box<Account<'info, MarketConfig>>,
+
+        /// CHECK: receives the royalty
+        #[account(mut, address = market_config.royalty_recipient)]
+        pub royalty_recipient: UncheckedAccount<'info>,
+
+        /// CHECK: the treasury, which collects protocol and transfer fees
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub owner: UncheckedAccount<'info>,
+
+        pub system_program: Program<'info, System>,
+        pub associated_token_program: Program<'info, anchor_spl::associated_token::AssociatedToken>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    /// Sells a weapon into a matching offer, with the same fees and transfer
+    /// rules as `buy`.
+    pub fn accept_offer(ctx: Context<AcceptOffer>) -> ProgramResult {
+        let seller = ctx.accounts.seller.key();
+        let bidder = ctx.accounts.bidder.key();
+        let mint = ctx.accounts.mint.key();
+        let offer = &ctx.accounts.offer;
+        let weapon = &ctx.accounts.weapon_account;
+        let now = Clock::get()?.unix_timestamp;
+
+        if now >= offer.expires_at {
+            return Err(ErrorCode::OfferExpired.into());
+        }
+
+        weapon.check_holder(&ctx.accounts.seller_token_account, &seller)?;
+        ctx.accounts
+            .upgrade_weapon
+            .check_collection_member(&ctx.accounts.metadata_account, &mint)?;
+        offer.check_target(&mint, ctx.accounts.mint_record.token_type, weapon)?;
+        weapon.check_not_broken()?;
+
+        let price = offer.price;
+        let transfer_fee = ctx.accounts.token_type_config.check_transfer(weapon, now)?;
+        let (royalty, treasury, proceeds) = ctx.accounts.market_config.split(price, transfer_fee)?;
+
+        let offer_info = ctx.accounts.offer.to_account_info();
+        crate::move_lamports(&offer_info, &ctx.accounts.royalty_recipient, royalty)?;
+        crate::move_lamports(&offer_info, &ctx.accounts.owner, treasury)?;
+        crate::move_lamports(&offer_info, &ctx.accounts.seller, proceeds)?;
+
+        anchor_spl::token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: ctx.accounts.seller_token_account.to_account_info(),
+                    to: ctx.accounts.bidder_token_account.to_account_info(),
+                    authority: ctx.accounts.seller.to_account_info(),
+                },
+            ),
+            1,
+        )?;
+
+        ctx.accounts.weapon_account.holder = bidder;
+
+        emit!(WeaponTransferred {
+            mint,
+            from: seller,
+            to: bidder,
+            fee: transfer_fee,
+        });
+        emit!(WeaponSold {
+            mint,
+            seller,
+            buyer: bidder,
+            price,
+            royalty,
+        });
+
+        Ok(())
+    }
+
+    /// A weapon lent out by its holder. The lender keeps the NFT, but its token
+    /// account stays frozen until the rental ends, and the game treats
+    /// `borrower` as the weapon's user while the rental is active.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct Rental {
+        pub lender: Pubkey,
+        pub borrower: Pubkey,
+        /// Lamports the borrower pays the lender to start the rental.
+        pub fee: u64,
+        pub duration: i64,
+        /// When the rental ends, 0 until the borrower has accepted it.
+        pub expires_at: i64,
+    }
+
+    impl Rental {
+        pub fn is_active(&self, now: i64) -> bool {
+            self.expires_at != 0 && now < self.expires_at
+        }
+    }
+
+    #[derive(Accounts)]
+    pub struct Lend<'info> {
+        #[account(mut)]
+        pub lender: Signer<'info>,
+
+        pub mint: Account<'info, anchor_spl::token::Mint>,
+
+        #[account(mut)]
+        pub lender_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        /// CHECK: the mint's master edition, checked by Metaplex
+        pub edition: UncheckedAccount<'info>,
+
+        /// CHECK: PDA that freezes lent and soulbound weapons, holds no data
+        #[account(seeds = [b"freezeAuthority"], bump)]
+        pub freeze_authority: UncheckedAccount<'info>,
+
+        #[account(
+            init,
+            payer = lender,
+            space = 8 + Rental::INIT_SPACE,
+            seeds = [b"rental", mint.key().as_ref()], bump
+        )]
+        pub rental: Box<Account<'info, Rental>>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        /// CHECK: checked to be the mint's metadata, in our verified collection
+        pub metadata_account: UncheckedAccount<'info>,
+
+        #[account(seeds = [b"mintRecord", mint.key().as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        pub system_program: Program<'info, System>,
+        pub token_program: Program<'info, Token>,
+
+        /// CHECK: must be Metaplex, which does the freeze
+        #[account(address = mpl_token_metadata::ID)]
+        pub token_metadata_program: UncheckedAccount<'info>,
+    }
+
+    /// Offers a weapon to `borrower` for `duration` seconds against `fee`
+    /// lamports. The weapon is frozen in the lender's wallet right away, so it
+    /// can't be sold or moved while the offer or the rental stands.
+    pub fn lend(ctx: Context<Lend>, borrower: Pubkey, duration: i64, fee: u64) -> ProgramResult {
+        let lender = ctx.accounts.lender.key();
+        let mint = ctx.accounts.mint.key();
+        let token_account = &ctx.accounts.lender_token_account;
+
+        if token_account.mint != mint || token_account.owner != lender || token_account.amount != 1 {
+            return Err(ErrorCode::InvalidTokenOwner.into());
+        }
+
+        if duration <= 0 || borrower == lender {
+            return Err(ErrorCode::InvalidRental.into());
+        }
+
+        // Soulbound weapons stay with whoever earned them, usage included.
+        if ctx.accounts.token_type_config.soulbound {
+            return Err(ErrorCode::Soulbound.into());
+        }
+
+        ctx.accounts
+            .upgrade_weapon
+            .check_collection_member(&ctx.accounts.metadata_account, &mint)?;
+
+        anchor_spl::token::approve(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::Approve {
+                    to: ctx.accounts.lender_token_account.to_account_info(),
+                    delegate: ctx.accounts.freeze_authority.to_account_info(),
+                    authority: ctx.accounts.lender.to_account_info(),
+                },
+            ),
+            1,
+        )?;
+
+        crate::set_weapon_frozen(
+            true,
+            &ctx.accounts.freeze_authority,
+            &ctx.accounts.lender_token_account.to_account_info(),
+            &ctx.accounts.edition,
+            &ctx.accounts.mint.to_account_info(),
+            &ctx.accounts.token_program,
+        )?;
+
+        ctx.accounts.rental.set_inner(Rental {
+            lender,
+            borrower,
+            fee,
+            duration,
+            expires_at: 0,
+        });
+
+        emit!(WeaponLent {
+            mint,
+            lender,
+            borrower,
+            fee,
+            duration,
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct Borrow<'info> {
+        #[account(mut)]
+        pub borrower: Signer<'info>,
+
+        /// CHECK: the rental's lender, who is paid the fee
+        #[account(mut)]
+        pub lender: UncheckedAccount<'info>,
+
+        /// CHECK: only used to find the rental
+        pub mint: UncheckedAccount<'info>,
+
+        #[account(mut, has_one = lender, has_one = borrower, seeds = [b"rental", mint.key().as_ref()], bump)]
+        pub rental: Box<Account<'info, Rental>>,
+
+        pub system_program: Program<'info, System>,
+    }
+
+    /// Accepts a rental offer: the borrower pays the fee and the clock starts.
+    /// `fee` must match the rental's, as `price` does for `buy`.
+    pub fn borrow(ctx: Context<Borrow>, fee: u64) -> ProgramResult {
+        let rental = &mut ctx.accounts.rental;
+
+        if rental.expires_at != 0 {
+            return Err(ErrorCode::RentalActive.into());
+        }
+
+        if rental.fee != fee {
+            return Err(ErrorCode::PriceChanged.into());
+        }
+
+        crate::transfer_lamports(
+            &ctx.accounts.system_program.to_account_info(),
+            &ctx.accounts.borrower.to_account_info(),
+            &ctx.accounts.lender.to_account_info(),
+            rental.fee,
+        )?;
+
+        rental.expires_at = Clock::get()?
+            .unix_timestamp
+            .checked_add(rental.duration)
+            .ok_or(ErrorCode::InvalidRental)?;
+
+        emit!(WeaponBorrowed {
+            mint: ctx.accounts.mint.key(),
+            borrower: rental.borrower,
+            expires_at: rental.expires_at,
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct EndRental<'info> {
+        #[account(mut)]
+        pub lender: Signer<'info>,
+
+        pub mint: Account<'info, anchor_spl::token::Mint>,
+
+        #[account(mut, token::mint = mint, token::authority = lender)]
+        pub lender_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        /// CHECK: the mint's master edition, checked by Metaplex
+        pub edition: UncheckedAccount<'info>,
+
+        /// CHECK: PDA that freezes lent and soulbound weapons, holds no data
+        #[account(seeds = [b"freezeAuthority"], bump)]
+        pub freeze_authority: UncheckedAccount<'info>,
+
+        #[account(mut, has_one = lender, close = lender, seeds = [b"rental", mint.key().as_ref()], bump)]
+        pub rental: Box<Account<'info, Rental>>,
+
+        pub token_program: Program<'info, Token>,
+
+        /// CHECK: must be Metaplex, which does the thaw
+        #[account(address = mpl_token_metadata::ID)]
+        pub token_metadata_program: UncheckedAccount<'info>,
+    }
+
+    /// Gives the lender full control of the weapon back, once the rental has
+    /// expired or if it was never accepted.
+    pub fn end_rental(ctx: Context<EndRental>) -> ProgramResult {
+        if ctx.accounts.rental.is_active(Clock::get()?.unix_timestamp) {
+            return Err(ErrorCode::RentalActive.into());
+        }
+
+        crate::set_weapon_frozen(
+            false,
+            &ctx.accounts.freeze_authority,
+            &ctx.accounts.lender_token_account.to_account_info(),
+            &ctx.accounts.edition,
+            &ctx.accounts.mint.to_account_info(),
+            &ctx.accounts.token_program,
+        )?;
+
+        anchor_spl::token::revoke(CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            anchor_spl::token::Revoke {
+                source: ctx.accounts.lender_token_account.to_account_info(),
+                authority: ctx.accounts.lender.to_account_info(),
+            },
+        ))?;
+
+        emit!(RentalEnded {
+            mint: ctx.accounts.mint.key(),
+        });
+
+        Ok(())
+    }
+
+    /// Reward settings of an `UpgradeWeapon`. Rewards are paid from the
+    /// `rewardVault` token account, which this config owns and the team tops up.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct StakingConfig {
+        pub reward_mint: Pubkey,
+        /// Reward base units per second for a level 0 weapon of each type. Each
+        /// level adds the same again, so a level 4 weapon earns five times this.
+        pub type_rates: [u64; 5],
+    }
+
+    impl StakingConfig {
+        pub fn rate(&self, token_type: u8, level: u64) -> u64 {
+            self.type_rates[token_type as usize].saturating_mul(level.saturating_add(1))
+        }
+    }
+
+    /// A staked weapon. `level` is the one rewards accrue at until the next
+    /// claim, so upgrading while staked pays off from then on.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct StakeAccount {
+        pub staker: Pubkey,
+        pub upgrade_weapon: Pubkey,
+        pub token_type: u8,
+        pub level: u64,
+        pub staked_at: i64,
+        pub last_claimed_at: i64,
+        /// Whether staking froze the token account, as opposed to finding it
+        /// already frozen because the weapon is soulbound.
+        pub froze: bool,
+        /// Rewards accrued but not paid yet because the vault ran short.
+        pub owed: u64,
+    }
+
+    impl StakeAccount {
+        /// Works out the rewards accrued since the last claim and starts a new
+        /// period at the weapon's current `level`.
+        pub fn settle(
+            &mut self,
+            config: &StakingConfig,
+            level: u64,
+            now: i64,
+        ) -> u64 {
+            let elapsed = now.saturating_sub(self.last_claimed_at).max(0) as u64;
+            let reward = config
+                .rate(self.token_type, self.level)
+                .saturating_mul(elapsed);
+
+            self.last_claimed_at = now;
+            self.level = level;
+
+            reward
+        }
+    }
+
+    #[derive(Accounts)]
+    pub struct SetStakingConfig<'info> {
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(
+            init_if_needed,
+            payer = authority,
+            space = 8 + StakingConfig::INIT_SPACE,
+            seeds = [b"staking", upgrade_weapon.key().as_ref()], bump
+        )]
+        pub staking_config: Box<Account<'info, StakingConfig>>,
+
+        pub reward_mint: Account<'info, anchor_spl::token::Mint>,
+
+        #[account(
+            init_if_needed,
+            payer = authority,
+            token::mint = reward_mint,
+            token::authority = staking_config,
+            seeds = [b"rewardVault", upgrade_weapon.key().as_ref()], bump
+        )]
+        pub reward_vault: Box<Account<'info, anchor_spl::token::TokenAccount>>,
+
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+
+        pub rent: Sysvar<'info, Rent>,
+        pub system_program: Program<'info, System>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    /// Sets the reward token and per-type rates. The reward mint can't change
+    /// once the vault exists, since the vault only holds that mint.
+    pub fn set_staking_config(ctx: Context<SetStakingConfig>, type_rates: [u64; 5]) -> ProgramResult {
+        ctx.accounts.staking_config.set_inner(StakingConfig {
+            reward_mint: ctx.accounts.reward_mint.key(),
+            type_rates,
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct Stake<'info> {
+        #[account(mut)]
+        pub staker: Signer<'info>,
+
+        pub mint: Account<'info, anchor_spl::token::Mint>,
+
+        #[account(mut)]
+        pub staker_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        /// CHECK: the mint's master edition, checked by Metaplex
+        pub edition: UncheckedAccount<'info>,
+
+        /// CHECK: PDA that freezes staked, lent and soulbound weapons, holds no data
+        #[account(seeds = [b"freezeAuthority"], bump)]
+        pub freeze_authority: UncheckedAccount<'info>,
+
+        #[account(
+            init,
+            payer = staker,
+            space = 8 + StakeAccount::INIT_SPACE,
+            seeds = [b"stake", mint.key().as_ref()], bump
+        )]
+        pub stake_account: Box<Account<'info, StakeAccount>>,
+
+        /// CHECK: must not exist, since lent weapons are frozen by the same PDA
+        #[account(seeds = [b"rental", mint.key().as_ref()], bump)]
+        pub rental: UncheckedAccount<'info>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        /// CHECK: checked to be the mint's metadata, in our verified collection
+        pub metadata_account: UncheckedAccount<'info>,
+
+        #[account(seeds = [b"weapon", mint.key().as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        #[account(seeds = [b"mintRecord", mint.key().as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        #[account(seeds = [b"staking", upgrade_weapon.key().as_ref()], bump)]
+        pub staking_config: Box<Account<'info, StakingConfig>>,
+
+        pub system_program: Program<'info, System>,
+        pub token_program: Program<'info, Token>,
+
+        /// CHECK: must be Metaplex, which does the freeze
+        #[account(address = mpl_token_metadata::ID)]
+        pub token_metadata_program: UncheckedAccount<'info>,
+    }
+
+    /// Locks a weapon in the staker's wallet and starts accruing rewards.
+    pub fn stake(ctx: Context<Stake>) -> ProgramResult {
+        let staker = ctx.accounts.staker.key();
+        let mint = ctx.accounts.mint.key();
+        let token_account = &ctx.accounts.staker_token_account;
+
+        if token_account.mint != mint || token_account.owner != staker || token_account.amount != 1 {
+            return Err(ErrorCode::InvalidTokenOwner.into());
+        }
+
+        if !ctx.accounts.rental.data_is_empty() || ctx.accounts.weapon_account.equipped_to != Pubkey::default() {
+            return Err(ErrorCode::WeaponLocked.into());
+        }
+
+        ctx.accounts
+            .upgrade_weapon
+            .check_collection_member(&ctx.accounts.metadata_account, &mint)?;
+
+        if ctx.accounts.token_type_config.is_gem {
+            return Err(ErrorCode::NotAWeapon.into());
+        }
+
+        // A bound soulbound weapon is already frozen by us and stays that way.
+        let froze = !token_account.is_frozen();
+
+        if !froze && token_account.delegate != COption::Some(ctx.accounts.freeze_authority.key()) {
+            return Err(ErrorCode::WeaponLocked.into());
+        }
+
+        if froze {
+            anchor_spl::token::approve(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    anchor_spl::token::Approve {
+                        to: ctx.accounts.staker_token_account.to_account_info(),
+                        delegate: ctx.accounts.freeze_authority.to_account_info(),
+                        authority: ctx.accounts.staker.to_account_info(),
+                    },
+                ),
+                1,
+            )?;
+
+            crate::set_weapon_frozen(
+                true,
+                &ctx.accounts.freeze_authority,
+                &ctx.accounts.staker_token_account.to_account_info(),
+                &ctx.accounts.edition,
+                &ctx.accounts.mint.to_account_info(),
+                &ctx.accounts.token_program,
+            )?;
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+
+        ctx.accounts.stake_account.set_inner(StakeAccount {
+            staker,
+            upgrade_weapon: ctx.accounts.upgrade_weapon.key(),
+            token_type: ctx.accounts.mint_record.token_type,
+            level: ctx.accounts.weapon_account.level,
+            staked_at: now,
+            last_claimed_at: now,
+            froze,
+            owed: 0,
+        });
+
+        emit!(WeaponStaked { mint, staker });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct Claim<'info> {
+        #[account(mut)]
+        pub staker: Signer<'info>,
+
+        /// CHECK: only used to find the stake and weapon
+        pub mint: UncheckedAccount<'info>,
+
+        #[account(mut, has_one = staker, has_one = upgrade_weapon, seeds = [b"stake", mint.key().as_ref()], bump)]
+        pub stake_account: Box<Account<'info, StakeAccount>>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(seeds = [b"weapon", mint.key().as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        #[account(seeds = [b"staking", upgrade_weapon.key().as_ref()], bump)]
+        pub staking_config: Box<Account<'info, StakingConfig>>,
+
+        #[account(address = staking_config.reward_mint)]
+        pub reward_mint: Account<'info, anchor_spl::token::Mint>,
+
+        #[account(mut, seeds = [b"rewardVault", upgrade_weapon.key().as_ref()], bump)]
+        pub reward_vault: Box<Account<'info, anchor_spl::token::TokenAccount>>,
 
-        /// CHECK: We will create this outside
         #[account(
-            init,
-            payer = authority,
-            space = 8 + 2 + 4 + 200 + 1, seeds = [b"weapon", mint.key().as_ref()], bump
+            init_if_needed,
+            payer = staker,
+            associated_token::mint = reward_mint,
+            associated_token::authority = staker
         )]
-        pub weapon_account: Account<'info, Weapon>,
+        pub staker_reward_account: Box<Account<'info, anchor_spl::token::TokenAccount>>,
+
+        pub system_program: Program<'info, System>,
+        pub associated_token_program: Program<'info, anchor_spl::associated_token::AssociatedToken>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    impl<'info> Claim<'info> {
+        /// Pays out everything the stake accrued so far, or as much as the vault
+        /// holds. Returns what is still owed.
+        fn pay_rewards(&mut self, staking_bump: u8) -> std::result::Result<u64, ProgramError> {
+            let accrued = self
+                .stake_account
+                .settle(&self.staking_config, self.weapon_account.level, Clock::get()?.unix_timestamp)
+                .saturating_add(self.stake_account.owed);
+            let reward = accrued.min(self.reward_vault.amount);
+
+            self.stake_account.owed = accrued - reward;
+
+            if reward > 0 {
+                let upgrade_weapon = self.upgrade_weapon.key();
+
+                anchor_spl::token::transfer(
+                    CpiContext::new_with_signer(
+                        self.token_program.to_account_info(),
+                        anchor_spl::token::Transfer {
+                            from: self.reward_vault.to_account_info(),
+                            to: self.staker_reward_account.to_account_info(),
+                            authority: self.staking_config.to_account_info(),
+                        },
+                        &[&[b"staking", upgrade_weapon.as_ref(), &[staking_bump]]],
+                    ),
+                    reward,
+                )?;
+            }
+
+            emit!(RewardsClaimed {
+                mint: self.weapon_account.mint,
+                staker: self.staker.key(),
+                amount: reward,
+            });
+
+            Ok(self.stake_account.owed)
+        }
+    }
+
+    /// Pays out the stake's rewards. Whatever the vault can't cover stays owed
+    /// for a later claim.
+    pub fn claim(ctx: Context<Claim>) -> ProgramResult {
+        let staking_bump = *ctx.bumps.get("staking_config").unwrap();
+
+        ctx.accounts.pay_rewards(staking_bump)?;
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct Unstake<'info> {
+        pub claim: Claim<'info>,
+
+        #[account(mut, token::mint = claim.mint, token::authority = claim.staker)]
+        pub staker_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        /// CHECK: the mint's master edition, checked by Metaplex
+        pub edition: UncheckedAccount<'info>,
+
+        /// CHECK: PDA that freezes staked, lent and soulbound weapons, holds no data
+        #[account(seeds = [b"freezeAuthority"], bump)]
+        pub freeze_authority: UncheckedAccount<'info>,
+
+        /// CHECK: must be Metaplex, which does the thaw
+        #[account(address = mpl_token_metadata::ID)]
+        pub token_metadata_program: UncheckedAccount<'info>,
+    }
+
+    /// Pays out the remaining rewards and unlocks the weapon. A short vault
+    /// never keeps the weapon locked: what it can't cover is forfeited and
+    /// reported on `WeaponUnstaked`, so stakers wanting all of it should wait
+    /// for the vault to be topped up and `claim` first.
+    pub fn unstake(ctx: Context<Unstake>) -> ProgramResult {
+        let staking_bump = Pubkey::find_program_address(
+            &[b"staking", ctx.accounts.claim.upgrade_weapon.key().as_ref()],
+            &crate::ID,
+        )
+        .1;
+
+        let forfeited = ctx.accounts.claim.pay_rewards(staking_bump)?;
+
+        let claim = &ctx.accounts.claim;
+
+        if claim.stake_account.froze {
+            crate::set_weapon_frozen(
+                false,
+                &ctx.accounts.freeze_authority,
+                &ctx.accounts.staker_token_account.to_account_info(),
+                &ctx.accounts.edition,
+                &claim.mint,
+                &claim.token_program,
+            )?;
+
+            anchor_spl::token::revoke(CpiContext::new(
+                claim.token_program.to_account_info(),
+                anchor_spl::token::Revoke {
+                    source: ctx.accounts.staker_token_account.to_account_info(),
+                    authority: claim.staker.to_account_info(),
+                },
+            ))?;
+        }
+
+        claim
+            .stake_account
+            .close(claim.staker.to_account_info())?;
+
+        emit!(WeaponUnstaked {
+            mint: claim.mint.key(),
+            staker: claim.staker.key(),
+            forfeited,
+        });
+
+        Ok(())
+    }
+
+    /// Equipment slots on a player profile.
+    pub const EQUIPMENT_SLOTS: usize = 3;
+
+    #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, InitSpace)]
+    pub struct EquipmentSlot {
+        /// Mint of the equipped weapon, the default key for an empty slot.
+        pub mint: Pubkey,
+        /// Whether equipping froze the token account, as opposed to finding it
+        /// already frozen because the weapon is soulbound.
+        pub froze: bool,
+    }
+
+    /// A player's character: the weapons it has equipped, so the game can read
+    /// loadouts from one account instead of reconciling wallets itself.
+    #[account]
+    #[derive(InitSpace)]
+    pub struct PlayerProfile {
+        pub wallet: Pubkey,
+        pub slots: [EquipmentSlot; EQUIPMENT_SLOTS],
+    }
+
+    impl PlayerProfile {
+        fn slot_mut(&mut self, slot: u8) -> std::result::Result<&mut EquipmentSlot, ProgramError> {
+            Ok(self.slots.get_mut(slot as usize).ok_or(ErrorCode::InvalidSlot)?)
+        }
+    }
+
+    #[derive(Accounts)]
+    pub struct Equip<'info> {
+        #[account(mut)]
+        pub player: Signer<'info>,
+
+        #[account(
+            init_if_needed,
+            payer = player,
+            space = 8 + PlayerProfile::INIT_SPACE,
+            seeds = [b"profile", player.key().as_ref()], bump
+        )]
+        pub profile: Box<Account<'info, PlayerProfile>>,
+
+        pub mint: Account<'info, anchor_spl::token::Mint>,
 
-        /// CHECK: We will create this outside
         #[account(mut)]
+        pub player_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        /// CHECK: the mint's master edition, checked by Metaplex
         pub edition: UncheckedAccount<'info>,
 
-        /// CHECK: We will create this outside
+        /// CHECK: PDA that freezes locked weapons, holds no data
+        #[account(seeds = [b"freezeAuthority"], bump)]
+        pub freeze_authority: UncheckedAccount<'info>,
+
+        #[account(mut, seeds = [b"weapon", mint.key().as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        /// CHECK: must not exist, since staked weapons are frozen by the same PDA
+        #[account(seeds = [b"stake", mint.key().as_ref()], bump)]
+        pub stake_account: UncheckedAccount<'info>,
+
+        /// CHECK: must not exist, since lent weapons are frozen by the same PDA
+        #[account(seeds = [b"rental", mint.key().as_ref()], bump)]
+        pub rental: UncheckedAccount<'info>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        /// CHECK: checked to be the mint's metadata, in our verified collection
+        pub metadata_account: UncheckedAccount<'info>,
+
+        #[account(seeds = [b"mintRecord", mint.key().as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        pub system_program: Program<'info, System>,
+        pub token_program: Program<'info, Token>,
+
+        /// CHECK: must be Metaplex, which does the freeze
+        #[account(address = mpl_token_metadata::ID)]
+        pub token_metadata_program: UncheckedAccount<'info>,
+    }
+
+    /// Puts a weapon in an empty `slot` of the player's profile and locks it in
+    /// their wallet until it is unequipped.
+    pub fn equip(ctx: Context<Equip>, slot: u8) -> ProgramResult {
+        let player = ctx.accounts.player.key();
+        let mint = ctx.accounts.mint.key();
+        let token_account = &ctx.accounts.player_token_account;
+
+        if token_account.mint != mint || token_account.owner != player || token_account.amount != 1 {
+            return Err(ErrorCode::InvalidTokenOwner.into());
+        }
+
+        if ctx.accounts.weapon_account.equipped_to != Pubkey::default()
+            || !ctx.accounts.stake_account.data_is_empty()
+            || !ctx.accounts.rental.data_is_empty()
+        {
+            return Err(ErrorCode::WeaponLocked.into());
+        }
+
+        ctx.accounts
+            .upgrade_weapon
+            .check_collection_member(&ctx.accounts.metadata_account, &mint)?;
+
+        // Gems only count once socketed into a weapon.
+        if ctx.accounts.token_type_config.is_gem {
+            return Err(ErrorCode::NotAWeapon.into());
+        }
+
+        let froze = !token_account.is_frozen();
+
+        if !froze && token_account.delegate != COption::Some(ctx.accounts.freeze_authority.key()) {
+            return Err(ErrorCode::WeaponLocked.into());
+        }
+
+        let profile_key = ctx.accounts.profile.key();
+        let profile = &mut ctx.accounts.profile;
+        profile.wallet = player;
+
+        let equipment_slot = profile.slot_mut(slot)?;
+        if equipment_slot.mint != Pubkey::default() {
+            return Err(ErrorCode::SlotOccupied.into());
+        }
+        *equipment_slot = EquipmentSlot { mint, froze };
+
+        if froze {
+            anchor_spl::token::approve(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    anchor_spl::token::Approve {
+                        to: ctx.accounts.player_token_account.to_account_info(),
+                        delegate: ctx.accounts.freeze_authority.to_account_info(),
+                        authority: ctx.accounts.player.to_account_info(),
+                    },
+                ),
+                1,
+            )?;
+
+            crate::set_weapon_frozen(
+                true,
+                &ctx.accounts.freeze_authority,
+                &ctx.accounts.player_token_account.to_account_info(),
+                &ctx.accounts.edition,
+                &ctx.accounts.mint.to_account_info(),
+                &ctx.accounts.token_program,
+            )?;
+        }
+
+        ctx.accounts.weapon_account.equipped_to = profile_key;
+
+        emit!(WeaponEquipped {
+            wallet: player,
+            mint,
+            slot,
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct Unequip<'info> {
+        pub player: Signer<'info>,
+
+        #[account(mut, seeds = [b"profile", player.key().as_ref()], bump)]
+        pub profile: Box<Account<'info, PlayerProfile>>,
+
+        pub mint: Account<'info, anchor_spl::token::Mint>,
+
+        #[account(mut, token::mint = mint, token::authority = player)]
+        pub player_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        /// CHECK: the mint's master edition, checked by Metaplex
+        pub edition: UncheckedAccount<'info>,
+
+        /// CHECK: PDA that freezes locked weapons, holds no data
+        #[account(seeds = [b"freezeAuthority"], bump)]
+        pub freeze_authority: UncheckedAccount<'info>,
+
+        #[account(mut, seeds = [b"weapon", mint.key().as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        pub token_program: Program<'info, Token>,
+
+        /// CHECK: must be Metaplex, which does the thaw
+        #[account(address = mpl_token_metadata::ID)]
+        pub token_metadata_program: UncheckedAccount<'info>,
+    }
+
+    /// Empties `slot` and unlocks the weapon that was in it.
+    pub fn unequip(ctx: Context<Unequip>, slot: u8) -> ProgramResult {
+        let mint = ctx.accounts.mint.key();
+        let equipment_slot = ctx.accounts.profile.slot_mut(slot)?;
+
+        if equipment_slot.mint != mint {
+            return Err(ErrorCode::InvalidSlot.into());
+        }
+
+        let froze = equipment_slot.froze;
+        *equipment_slot = EquipmentSlot::default();
+
+        if froze {
+            crate::set_weapon_frozen(
+                false,
+                &ctx.accounts.freeze_authority,
+                &ctx.accounts.player_token_account.to_account_info(),
+                &ctx.accounts.edition,
+                &ctx.accounts.mint.to_account_info(),
+                &ctx.accounts.token_program,
+            )?;
+
+            anchor_spl::token::revoke(CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::Revoke {
+                    source: ctx.accounts.player_token_account.to_account_info(),
+                    authority: ctx.accounts.player.to_account_info(),
+                },
+            ))?;
+        }
+
+        ctx.accounts.weapon_account.equipped_to = Pubkey::default();
+
+        emit!(WeaponUnequipped {
+            wallet: ctx.accounts.player.key(),
+            mint,
+            slot,
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct CharacterStats<'info> {
+        #[account(seeds = [b"profile", profile.wallet.as_ref()], bump)]
+        pub profile: Box<Account<'info, PlayerProfile>>,
+        // Then the `Weapon` account of every equipped slot, in slot order.
+    }
+
+    /// Emits the summed stats of every weapon a profile has equipped.
+    pub fn character_stats<'info>(ctx: Context<'_, '_, '_, 'info, CharacterStats<'info>>) -> ProgramResult {
+        let equipped = ctx
+            .accounts
+            .profile
+            .slots
+            .iter()
+            .filter(|slot| slot.mint != Pubkey::default());
+
+        let mut weapons = ctx.remaining_accounts.iter();
+        let mut stats = [0u64; 6];
+
+        for slot in equipped {
+            let weapon_info = weapons.next().ok_or(ErrorCode::InvalidSlot)?;
+            let (address, _) = Pubkey::find_program_address(&[b"weapon", slot.mint.as_ref()], &crate::ID);
+
+            if weapon_info.key() != address || weapon_info.owner != &crate::ID {
+                return Err(ErrorCode::InvalidSlot.into());
+            }
+
+            let weapon = Weapon::try_deserialize(&mut &weapon_info.try_borrow_data()?[..])?;
+
+            for (total, stat) in stats.iter_mut().zip(weapon.stats()) {
+                *total = total.saturating_add(stat);
+            }
+        }
+
+        emit!(CharacterStatsComputed {
+            wallet: ctx.accounts.profile.wallet,
+            level: stats[0],
+            hp: stats[1],
+            damage: stats[2],
+            mana: stats[3],
+            mp_regen: stats[4],
+            atk_speed: stats[5],
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct WearWeapon<'info> {
+        #[account(address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+
+        #[account(mut, seeds = [b"weapon", weapon_account.mint.as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+    }
+
+    /// Called by the game server after a match to take `amount` durability off
+    /// a weapon. A weapon worn down to zero is broken until repaired.
+    pub fn wear_weapon(ctx: Context<WearWeapon>, amount: u64) -> ProgramResult {
+        let weapon = &mut ctx.accounts.weapon_account;
+
+        weapon.wear = weapon.wear.saturating_add(amount).min(MAX_DURABILITY);
+        weapon.broken = weapon.wear == MAX_DURABILITY;
+
+        emit!(DurabilityChanged {
+            mint: weapon.mint,
+            durability: weapon.durability(),
+            broken: weapon.broken,
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct Repair<'info> {
         #[account(mut)]
-        pub collection: UncheckedAccount<'info>,
+        pub holder: Signer<'info>,
 
-        /// CHECK: We will create this outside
-        pub collection_mint: UncheckedAccount<'info>,
+        pub token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        #[account(mut, seeds = [b"weapon", weapon_account.mint.as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(seeds = [b"mintRecord", weapon_account.mint.as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+
+        /// CHECK: the treasury, which is paid for SOL repairs
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub owner: UncheckedAccount<'info>,
+
+        /// The holder's account of the repair token, for types repaired in one.
+        #[account(mut)]
+        pub holder_payment_account: Option<Account<'info, anchor_spl::token::TokenAccount>>,
+
+        /// The treasury's account of the repair token, for types repaired in one.
+        #[account(mut, token::authority = owner)]
+        pub treasury_payment_account: Option<Account<'info, anchor_spl::token::TokenAccount>>,
+
+        pub system_program: Program<'info, System>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    /// Restores a weapon to full durability, charging the holder the type's
+    /// repair price for every point restored.
+    pub fn repair(ctx: Context<Repair>) -> ProgramResult {
+        let weapon = &ctx.accounts.weapon_account;
+        weapon.check_holder(&ctx.accounts.token_account, &ctx.accounts.holder.key())?;
+
+        let config = &ctx.accounts.token_type_config;
+        let cost = weapon.wear.saturating_mul(config.repair_price);
+
+        match config.repair_mint {
+            Some(repair_mint) if cost > 0 => {
+                let (Some(from), Some(to)) = (
+                    &ctx.accounts.holder_payment_account,
+                    &ctx.accounts.treasury_payment_account,
+                ) else {
+                    return Err(ErrorCode::InvalidPaymentAccount.into());
+                };
+
+                if from.mint != repair_mint || to.mint != repair_mint {
+                    return Err(ErrorCode::InvalidPaymentAccount.into());
+                }
+
+                anchor_spl::token::transfer(
+                    CpiContext::new(
+                        ctx.accounts.token_program.to_account_info(),
+                        anchor_spl::token::Transfer {
+                            from: from.to_account_info(),
+                            to: to.to_account_info(),
+                            authority: ctx.accounts.holder.to_account_info(),
+                        },
+                    ),
+                    cost,
+                )?;
+            }
+            Some(_) => {}
+            None => crate::transfer_lamports(
+                &ctx.accounts.system_program.to_account_info(),
+                &ctx.accounts.holder.to_account_info(),
+                &ctx.accounts.owner,
+                cost,
+            )?,
+        }
+
+        let weapon = &mut ctx.accounts.weapon_account;
+        weapon.wear = 0;
+        weapon.broken = false;
+
+        emit!(DurabilityChanged {
+            mint: weapon.mint,
+            durability: weapon.durability(),
+            broken: false,
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct GrantXp<'info> {
+        #[account(address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub authority: Signer<'info>,
+
+        #[account(mut, seeds = [b"weapon", weapon_account.mint.as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(seeds = [b"mintRecord", weapon_account.mint.as_ref()], bump)]
+        pub mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[mint_record.token_type]], bump)]
+        pub token_type_config: Box<Account<'info, TokenTypeConfig>>,
+    }
+
+    /// Called by the game server to award a weapon `amount` XP, e.g. after a
+    /// match. Levels follow from the type's level table.
+    pub fn grant_xp(ctx: Context<GrantXp>, amount: u64) -> ProgramResult {
+        let weapon = &mut ctx.accounts.weapon_account;
+        let level_before = weapon.level;
+
+        ctx.accounts
+            .token_type_config
+            .grant_xp(weapon, amount, Clock::get()?.unix_timestamp)?;
+
+        emit!(XpGranted {
+            mint: weapon.mint,
+            amount,
+            xp: weapon.xp,
+            level: weapon.level,
+            levels_gained: weapon.level - level_before,
+        });
+
+        Ok(())
+    }
+
+    #[derive(Accounts)]
+    pub struct SocketGem<'info> {
+        #[account(mut)]
+        pub holder: Signer<'info>,
+
+        pub weapon_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+
+        #[account(mut, seeds = [b"weapon", weapon_account.mint.as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
 
-        /// CHECK: We will create this outside
-        pub collection_master_edition: UncheckedAccount<'info>,
+        pub gem_mint: Account<'info, anchor_spl::token::Mint>,
 
-        pub rent: Sysvar<'info, Rent>,
+        #[account(mut, token::mint = gem_mint, token::authority = holder)]
+        pub holder_gem_account: Account<'info, anchor_spl::token::TokenAccount>,
 
         #[account(
-            init_if_needed,
-            payer = authority,
-            space = 8 + 2 + 4 + 200 + 1, seeds = [b"mintedNFT", authority.key().as_ref()], bump
+            init,
+            payer = holder,
+            token::mint = gem_mint,
+            token::authority = weapon_account,
+            seeds = [b"gemVault", gem_mint.key().as_ref()], bump
         )]
-        pub nft_account: Box<Account<'info, MintedNFTAccount>>,
+        pub gem_vault: Box<Account<'info, anchor_spl::token::TokenAccount>>,
 
-        /// CHECK: We will create this outside
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(seeds = [b"mintRecord", weapon_account.mint.as_ref()], bump)]
+        pub weapon_mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[weapon_mint_record.token_type]], bump)]
+        pub weapon_config: Box<Account<'info, TokenTypeConfig>>,
+
+        #[account(seeds = [b"mintRecord", gem_mint.key().as_ref()], bump)]
+        pub gem_mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[gem_mint_record.token_type]], bump)]
+        pub gem_config: Box<Account<'info, TokenTypeConfig>>,
+
+        pub rent: Sysvar<'info, Rent>,
         pub system_program: Program<'info, System>,
-        /// CHECK: We will create this outside
         pub token_program: Program<'info, Token>,
-        /// CHECK: We will create this outside
-        pub token_metadata_program: UncheckedAccount<'info>,
-        pub associated_token_program: Program<'info, anchor_spl::associated_token::AssociatedToken>,
     }
 
-    pub fn transfer(ctx: Context<Transfer>) -> ProgramResult {
-        let cpi_ctx = CpiContext::new(
-            ctx.accounts.token_program.to_account_info(),
-            anchor_spl::token::Transfer {
-                from: ctx
-                    .accounts
-                    .from_association_token_account
-                    .to_account_info(),
-                to: ctx.accounts.to_association_token_account.to_account_info(),
-                authority: ctx.accounts.from.to_account_info(),
-            },
-        );
+    /// Locks a gem into an empty `socket` of a weapon. The gem is held in a
+    /// vault owned by the weapon, so it travels with it when it changes hands.
+    pub fn socket_gem(ctx: Context<SocketGem>, socket: u8) -> ProgramResult {
+        let holder = ctx.accounts.holder.key();
+        let gem_mint = ctx.accounts.gem_mint.key();
+        let gem_config = &ctx.accounts.gem_config;
 
-        anchor_spl::token::transfer(cpi_ctx, 1)?;
+        ctx.accounts
+            .weapon_account
+            .check_holder(&ctx.accounts.weapon_token_account, &holder)?;
 
-        Ok(())
-    }
+        if !gem_config.is_gem || ctx.accounts.weapon_config.is_gem {
+            return Err(ErrorCode::NotAGem.into());
+        }
 
-    pub fn burn(ctx: Context<Burn>) -> ProgramResult {
-        msg!("Burning token");
+        if socket >= ctx.accounts.weapon_config.sockets
+            || ctx.accounts.weapon_account.gems[socket as usize] != Pubkey::default()
+        {
+            return Err(ErrorCode::InvalidSocket.into());
+        }
 
-        invoke(
-            &mpl_token_metadata::instruction::burn_nft(
-                ctx.accounts.token_metadata_program.key(),
-                ctx.accounts.metadata_account.key(),
-                ctx.accounts.authority.key(),
-                ctx.accounts.mint.key(),
-                ctx.accounts.association_token_account.key(),
-                ctx.accounts.metadata_edition_account.key(),
-                ctx.accounts.token_program.key(),
-                None,
-            ),
-            &[
-                ctx.accounts.token_metadata_program.to_account_info(),
-                ctx.accounts.metadata_account.to_account_info(),
-                ctx.accounts.mint.to_account_info(),
+        anchor_spl::token::transfer(
+            CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
-                ctx.accounts.authority.to_account_info(),
-                ctx.accounts.association_token_account.to_account_info(),
-                ctx.accounts.metadata_edition_account.to_account_info(),
-            ],
+                anchor_spl::token::Transfer {
+                    from: ctx.accounts.holder_gem_account.to_account_info(),
+                    to: ctx.accounts.gem_vault.to_account_info(),
+                    authority: ctx.accounts.holder.to_account_info(),
+                },
+            ),
+            1,
         )?;
-        msg!("Burning token completed");
+
+        let weapon = &mut ctx.accounts.weapon_account;
+        weapon.gems[socket as usize] = gem_mint;
+        weapon.gem_bonuses[socket as usize] = gem_config.gem_stats;
+        weapon.refresh_stats(&ctx.accounts.weapon_config);
+
+        emit!(GemSocketed {
+            mint: weapon.mint,
+            gem: gem_mint,
+            socket,
+        });
 
         Ok(())
     }
 
     #[derive(Accounts)]
-    pub struct Transfer<'info> {
+    pub struct UnsocketGem<'info> {
         #[account(mut)]
-        pub from: Signer<'info>,
+        pub holder: Signer<'info>,
 
-        #[account(mut)]
-        pub from_association_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+        pub weapon_token_account: Account<'info, anchor_spl::token::TokenAccount>,
 
-        #[account(mut)]
-        pub mint: Account<'info, anchor_spl::token::Mint>,
+        #[account(mut, seeds = [b"weapon", weapon_account.mint.as_ref()], bump)]
+        pub weapon_account: Box<Account<'info, Weapon>>,
 
-        /// CHECK:
-        #[account(mut)]
-        pub to: UncheckedAccount<'info>,
+        pub gem_mint: Account<'info, anchor_spl::token::Mint>,
 
         #[account(
             init_if_needed,
-            payer = from,
-            associated_token::mint = mint,
-            associated_token::authority = to
+            payer = holder,
+            associated_token::mint = gem_mint,
+            associated_token::authority = holder
         )]
-        pub to_association_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+        pub holder_gem_account: Box<Account<'info, anchor_spl::token::TokenAccount>>,
+
+        #[account(mut, seeds = [b"gemVault", gem_mint.key().as_ref()], bump)]
+        pub gem_vault: Box<Account<'info, anchor_spl::token::TokenAccount>>,
+
+        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
+
+        #[account(seeds = [b"mintRecord", weapon_account.mint.as_ref()], bump)]
+        pub weapon_mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[weapon_mint_record.token_type]], bump)]
+        pub weapon_config: Box<Account<'info, TokenTypeConfig>>,
+
+        #[account(seeds = [b"mintRecord", gem_mint.key().as_ref()], bump)]
+        pub gem_mint_record: Box<Account<'info, MintRecord>>,
+
+        #[account(seeds = [b"tokenType", upgrade_weapon.key().as_ref(), &[gem_mint_record.token_type]], bump)]
+        pub gem_config: Box<Account<'info, TokenTypeConfig>>,
+
+        /// CHECK: the treasury, which is paid the unsocket price
+        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
+        pub owner: UncheckedAccount<'info>,
 
         pub system_program: Program<'info, System>,
         pub associated_token_program: Program<'info, anchor_spl::associated_token::AssociatedToken>,
         pub token_program: Program<'info, Token>,
     }
 
-    #[derive(Accounts)]
-    #[instruction(name: String, symbol: String)]
-    pub struct Initialize<'info> {
-        #[account(init, payer = user, space = 1000 + size_of::<UpgradeWeapon>())]
-        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
-        #[account(mut)]
-        /// CHECK: No checks through types are necessary for the `user` account.
-        pub user: Signer<'info>,
-        pub system_program: Program<'info, System>,
-    }
+    /// Takes the gem out of `socket` and gives it to the weapon's holder, for
+    /// the weapon type's unsocket price.
+    pub fn unsocket_gem(ctx: Context<UnsocketGem>, socket: u8) -> ProgramResult {
+        let holder = ctx.accounts.holder.key();
+        let gem_mint = ctx.accounts.gem_mint.key();
+        let weapon_mint = ctx.accounts.weapon_account.mint;
 
-    #[derive(Accounts)]
-    #[instruction(token_uri: String)]
-    pub struct AddTokenType<'info> {
-        #[account(mut)]
-        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
-        #[account(signer)]
-        /// CHECK: No checks through types are necessary for the `user` account.
-        pub user: AccountInfo<'info>,
-    }
+        ctx.accounts
+            .weapon_account
+            .check_holder(&ctx.accounts.weapon_token_account, &holder)?;
 
-    #[derive(Accounts)]
-    #[instruction(token_type: u64, token_uri: String)]
-    pub struct SetTokenTypeURI<'info> {
-        #[account(mut)]
-        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
-        #[account(signer)]
-        /// CHECK: No checks through types are necessary for the `user` account.
-        pub user: AccountInfo<'info>,
-    }
+        if ctx.accounts.weapon_account.gems.get(socket as usize) != Some(&gem_mint) {
+            return Err(ErrorCode::InvalidSocket.into());
+        }
 
-    #[derive(Accounts)]
-    #[instruction(token_id: u64)]
-    pub struct TokenURI<'info> {
-        #[account(mut)]
-        pub upgrade_weapon: Box<Account<'info, UpgradeWeapon>>,
-        #[account(signer)]
-        /// CHECK: No checks through types are necessary for the `user` account.
-        pub user: AccountInfo<'info>,
-        pub token_account: Account<'info, TokenAccount>,
-    }
+        crate::transfer_lamports(
+            &ctx.accounts.system_program.to_account_info(),
+            &ctx.accounts.holder.to_account_info(),
+            &ctx.accounts.owner,
+            ctx.accounts.weapon_config.unsocket_price,
+        )?;
 
-    #[derive(Accounts)]
-    pub struct Burn<'info> {
-        /// CHECK: We will create this outside
-        #[account(mut)]
-        metadata_account: UncheckedAccount<'info>,
-        #[account(mut)]
-        pub authority: Signer<'info>,
-        #[account(mut)]
-        pub mint: Account<'info, anchor_spl::token::Mint>,
-        #[account(mut)]
-        pub association_token_account: Account<'info, anchor_spl::token::TokenAccount>,
+        let weapon_bump = *ctx.bumps.get("weapon_account").unwrap();
+        let signer_seeds: &[&[u8]] = &[b"weapon", weapon_mint.as_ref(), &[weapon_bump]];
 
-        /// CHECK: We will create this outside
-        #[account(mut)]
-        pub metadata_edition_account: UncheckedAccount<'info>,
+        anchor_spl::token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: ctx.accounts.gem_vault.to_account_info(),
+                    to: ctx.accounts.holder_gem_account.to_account_info(),
+                    authority: ctx.accounts.weapon_account.to_account_info(),
+                },
+                &[signer_seeds],
+            ),
+            1,
+        )?;
 
-        /// CHECK: We will create this outside
-        pub token_program: Program<'info, Token>,
-        /// CHECK: We will create this outside
-        pub token_metadata_program: UncheckedAccount<'info>,
-    }
+        anchor_spl::token::close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            anchor_spl::token::CloseAccount {
+                account: ctx.accounts.gem_vault.to_account_info(),
+                destination: ctx.accounts.holder.to_account_info(),
+                authority: ctx.accounts.weapon_account.to_account_info(),
+            },
+            &[signer_seeds],
+        ))?;
 
-    #[account]
-    pub struct TokenAccount {
-        pub owner: Pubkey,
-        pub token_id: u64,
-    }
+        let weapon = &mut ctx.accounts.weapon_account;
+        weapon.gems[socket as usize] = Pubkey::default();
+        weapon.gem_bonuses[socket as usize] = [0; 5];
+        weapon.refresh_stats(&ctx.accounts.weapon_config);
 
-    // Define errors
+        emit!(GemUnsocketed {
+            mint: weapon_mint,
+            gem: gem_mint,
+            socket,
+        });
 
-    #[derive(Debug)]
-    pub enum ErrorCode {
-        InvalidTokenType,
-        InvalidTokenId,
-        InvalidTokenOwner,
-        InvalidBalance,
-        DuplicatedDevice,
-        InvalidSignature,
-        NftLimitExceeded,
+        Ok(())
     }
+}
 
-    impl From<ErrorCode> for ProgramError {
-        fn from(err: ErrorCode) -> ProgramError {
-            match err {
-                ErrorCode::InvalidTokenType => ProgramError::Custom(1),
-                ErrorCode::InvalidTokenId => ProgramError::Custom(2),
-                ErrorCode::InvalidTokenOwner => ProgramError::Custom(3),
-                ErrorCode::InvalidBalance => ProgramError::Custom(4),
-                ErrorCode::DuplicatedDevice => ProgramError::Custom(5),
-                ErrorCode::InvalidSignature => ProgramError::Custom(6),
-                ErrorCode::NftLimitExceeded => ProgramError::Custom(8),
-            }
-        }
+/// Grows a program-owned `account` to `space` bytes, topping up its rent from
+/// `payer`. Our account types only ever gain fields at the end, so the new
+/// zeroed bytes deserialize as defaults.
+fn grow_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    space: usize,
+) -> ProgramResult {
+    if account.data_len() >= space {
+        return Ok(());
     }
 
-    // Define events
+    let lamports = Rent::get()?
+        .minimum_balance(space)
+        .saturating_sub(account.lamports());
 
-    #[event]
-    pub struct TokenURISuccess {
-        pub token_uri: String,
+    if lamports > 0 {
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                system_program.clone(),
+                anchor_lang::system_program::Transfer {
+                    from: payer.clone(),
+                    to: account.clone(),
+                },
+            ),
+            lamports,
+        )?;
     }
 
-    // Define additional structs
+    account.realloc(space, true)
+}
 
-    #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-    pub struct TokenType {
-        pub id: u64,
-        pub token_type: u64,
-    }
+/// Closes the program-owned `account`, sending its rent to `destination`.
+fn close_pda_account(account: &AccountInfo, destination: &AccountInfo) -> ProgramResult {
+    **destination.try_borrow_mut_lamports()? += account.lamports();
+    **account.try_borrow_mut_lamports()? = 0;
 
-    #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-    pub struct TokenDevice {
-        pub token_type: u8,
-        pub device_id: String,
-    }
+    account.assign(&anchor_lang::system_program::ID);
+    account.realloc(0, false)
+}
 
-    #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-    pub struct TokenTypeURI {
-        pub id: u64,
-        pub token_uri: String,
-        pub name: String,
+/// Moves `lamports` from the signer `from` to `to`, doing nothing for zero.
+fn transfer_lamports<'info>(
+    system_program: &AccountInfo<'info>,
+    from: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    lamports: u64,
+) -> ProgramResult {
+    if lamports == 0 {
+        return Ok(());
     }
 
-    #[account]
-    pub struct Weapon {
-        level: u64,
-        hp: u64,
-        damage: u64,
-        mana: u64,
-        mp_regen: u64,
-        atk_speed: u64,
+    anchor_lang::system_program::transfer(
+        CpiContext::new(
+            system_program.clone(),
+            anchor_lang::system_program::Transfer {
+                from: from.clone(),
+                to: to.clone(),
+            },
+        ),
+        lamports,
+    )?;
+
+    Ok(())
+}
+
+/// Moves `lamports` out of the program-owned `from`, which needs no signature.
+fn move_lamports(from: &AccountInfo, to: &AccountInfo, lamports: u64) -> ProgramResult {
+    **from.try_borrow_mut_lamports()? = from
+        .lamports()
+        .checked_sub(lamports)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    **to.try_borrow_mut_lamports()? += lamports;
+
+    Ok(())
+}
+
+/// Sends the NFT held in a listing's `escrow` to `destination` and closes the
+/// escrow, returning its rent to `seller`. Signs as the listing of `mint`.
+fn release_escrow<'info>(
+    escrow: &AccountInfo<'info>,
+    destination: &AccountInfo<'info>,
+    listing: &AccountInfo<'info>,
+    seller: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    mint: &Pubkey,
+    bump: u8,
+) -> ProgramResult {
+    let signer_seeds: &[&[u8]] = &[b"listing", mint.as_ref(), &[bump]];
+
+    anchor_spl::token::transfer(
+        CpiContext::new_with_signer(
+            token_program.clone(),
+            anchor_spl::token::Transfer {
+                from: escrow.clone(),
+                to: destination.clone(),
+                authority: listing.clone(),
+            },
+            &[signer_seeds],
+        ),
+        1,
+    )?;
+
+    anchor_spl::token::close_account(CpiContext::new_with_signer(
+        token_program.clone(),
+        anchor_spl::token::CloseAccount {
+            account: escrow.clone(),
+            destination: seller.clone(),
+            authority: listing.clone(),
+        },
+        &[signer_seeds],
+    ))?;
+
+    Ok(())
+}
+
+/// Freezes or thaws a weapon's token account through Metaplex, signing as the
+/// `freezeAuthority` PDA, which must be the account's delegate. Soulbound and
+/// lent weapons share this PDA; `lend` refuses soulbound types.
+fn set_weapon_frozen<'info>(
+    frozen: bool,
+    freeze_authority: &AccountInfo<'info>,
+    token_account: &AccountInfo<'info>,
+    edition: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+) -> ProgramResult {
+    let (address, bump) = Pubkey::find_program_address(&[b"freezeAuthority"], &crate::ID);
+    if address != *freeze_authority.key {
+        return Err(ProgramError::InvalidSeeds);
     }
 
-    #[derive(Accounts)]
-    pub struct UpgradeWeaponLevel<'info> {
-        #[account(mut)]
-        pub owner: Signer<'info>,
+    let instruction = if frozen {
+        mpl_token_metadata::instruction::freeze_delegated_account
+    } else {
+        mpl_token_metadata::instruction::thaw_delegated_account
+    };
 
-        #[account(mut)]
-        pub weapon_account: Account<'info, Weapon>,
+    invoke_signed(
+        &instruction(
+            mpl_token_metadata::ID,
+            address,
+            *token_account.key,
+            *edition.key,
+            *mint.key,
+        ),
+        &[
+            freeze_authority.clone(),
+            token_account.clone(),
+            edition.clone(),
+            mint.clone(),
+            token_program.clone(),
+        ],
+        &[&[b"freezeAuthority", &[bump]]],
+    )
+}
 
-        #[account(mut, address=solana_program::pubkey!("2Jxxms25kixad7877JuD5UvDDp2F4Zf3X2qbceKv3PTj"))]
-        pub authority: Signer<'info>,
+/// Creates the program-owned PDA `account` derived from `seeds`, for accounts
+/// that can't be declared with Anchor's `init`.
+fn create_pda_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    seeds: &[&[u8]],
+    space: usize,
+) -> ProgramResult {
+    let (address, bump) = Pubkey::find_program_address(seeds, &crate::ID);
+    if address != *account.key {
+        return Err(ProgramError::InvalidSeeds);
     }
 
-    pub fn upgrade_weapon_level(
-        ctx: Context<UpgradeWeaponLevel>,
-        level: [u64; 6],
-    ) -> ProgramResult {
-        let metadata_acc: &mut Account<'_, Weapon> = &mut ctx.accounts.weapon_account;
+    let bump = [bump];
+    let mut signer_seeds = seeds.to_vec();
+    signer_seeds.push(&bump);
 
-        metadata_acc.level = level[0];
-        metadata_acc.hp = level[1];
-        metadata_acc.damage = level[2];
-        metadata_acc.mana = level[3];
-        metadata_acc.mp_regen = level[4];
-        metadata_acc.atk_speed = level[5];
+    anchor_lang::system_program::create_account(
+        CpiContext::new_with_signer(
+            system_program.clone(),
+            anchor_lang::system_program::CreateAccount {
+                from: payer.clone(),
+                to: account.clone(),
+            },
+            &[&signer_seeds],
+        ),
+        Rent::get()?.minimum_balance(space),
+        space as u64,
+        &crate::ID,
+    )?;
 
-        Ok(())
+    Ok(())
+}
+
+/// Seed for rolling the rarity of the weapon `mint` from `seed`, random bytes
+/// picked by our backend. Tying it to the mint keeps one seed from rolling
+/// several weapons.
+fn rarity_seed(seed: &[u8; 32], mint: &Pubkey) -> [u8; 32] {
+    solana_program::keccak::hashv(&[seed, mint.as_ref()]).to_bytes()
+}
+
+/// Bytes our backend signs to hand out `seed` for the weapon `mint`.
+fn rarity_seed_message(mint: &Pubkey, seed: &[u8; 32]) -> Vec<u8> {
+    let mut message = b"rarity".to_vec();
+    message.extend_from_slice(mint.as_ref());
+    message.extend_from_slice(seed);
+    message
+}
+
+/// Checks a Merkle `proof` that `leaf` is in the tree with `root`. Pairs are
+/// hashed in sorted order, so proofs carry no left/right flags.
+fn verify_merkle_proof(root: &[u8; 32], leaf: [u8; 32], proof: &[[u8; 32]]) -> bool {
+    let computed = proof.iter().fold(leaf, |node, sibling| {
+        if node <= *sibling {
+            anchor_lang::solana_program::keccak::hashv(&[&node, sibling]).to_bytes()
+        } else {
+            anchor_lang::solana_program::keccak::hashv(&[sibling, &node]).to_bytes()
+        }
+    });
+
+    computed == *root
+}
+
+/// Checks that an instruction before the current one is an Ed25519 program
+/// instruction verifying exactly one signature by `signer` over `message`,
+/// with all of its data stored inline in that instruction. Looking further
+/// back than the previous instruction lets one transaction carry several
+/// attestations, e.g. a device's and a rarity seed's.
+fn verify_ed25519_instruction(
+    instructions: &AccountInfo,
+    signer: &Pubkey,
+    message: &[u8],
+) -> ProgramResult {
+    use anchor_lang::solana_program::sysvar::instructions::{
+        load_current_index_checked, load_instruction_at_checked,
+    };
+    use crate::upgrade_weapon::ErrorCode;
+
+    let current_index = load_current_index_checked(instructions)? as usize;
+
+    for index in 0..current_index {
+        let ix = load_instruction_at_checked(index, instructions)?;
+        if ix.program_id != anchor_lang::solana_program::ed25519_program::ID || !ix.accounts.is_empty() {
+            continue;
+        }
+
+        // Layout: [count: u8, padding: u8, offsets: 7 x u16 per signature, ...data]
+        let data = &ix.data;
+        if data.len() < 16 || data[0] != 1 {
+            continue;
+        }
+
+        let read_u16 = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+        let public_key_offset = read_u16(6) as usize;
+        let message_offset = read_u16(10) as usize;
+        let message_size = read_u16(12) as usize;
+
+        // Every section must live in the Ed25519 instruction itself.
+        if [read_u16(4), read_u16(8), read_u16(14)].iter().any(|&index| index != u16::MAX) {
+            continue;
+        }
+
+        let signed_key = data.get(public_key_offset..public_key_offset + 32);
+        let signed_message = data.get(message_offset..message_offset + message_size);
+
+        if signed_key == Some(signer.as_ref()) && signed_message == Some(message) {
+            return Ok(());
+        }
     }
-}
\ No newline at end of file
+
+    Err(ErrorCode::InvalidSignature.into())
+}
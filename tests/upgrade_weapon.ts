@@ -1,12 +1,14 @@
 import { publicKey } from '@project-serum/borsh';
 import { assert } from "chai";
 import { SystemProgram } from "@solana/web3.js";
-import { TOKEN_PROGRAM_ID } from "@solana/spl-token";
+import { ASSOCIATED_TOKEN_PROGRAM_ID, TOKEN_PROGRAM_ID, createMint, getAccount, getAssociatedTokenAddress, mintTo } from "@solana/spl-token";
 
 import BN from "bn.js";
 import { AnchorProvider } from "@project-serum/anchor";
 import { Metaplex, Signer, keypairIdentity } from "@metaplex-foundation/js";
-import { Connection, clusterApiUrl, Keypair, PublicKey, ComputeBudgetProgram } from "@solana/web3.js";
+import { Connection, clusterApiUrl, Keypair, PublicKey, ComputeBudgetProgram, Ed25519Program, SYSVAR_INSTRUCTIONS_PUBKEY, SYSVAR_RENT_PUBKEY, Transaction } from "@solana/web3.js";
+import { createHash } from "crypto";
+import { keccak_256 } from "js-sha3";
 import base58 from 'bs58';
 import * as borsh from "@project-serum/borsh";
 
@@ -32,6 +34,210 @@ describe("UpgradeWeapon", () => {
 
   const authority = anchor.AnchorProvider.env().wallet;
 
+  const programId = new PublicKey("C7KQdF6atRDnJe9cCLomcESLFZCtYa9SEpRT5i9Y4J3u");
+  const weaponCollection = new PublicKey("24wrBYYngSuLvcyNJPkPVwDb9ifJGTLh6DZPQjYzfmoV");
+
+  const findAddress = async (seeds: Buffer[], owner: PublicKey = programId) =>
+    (await anchor.web3.PublicKey.findProgramAddress(seeds, owner))[0];
+
+  const metadataAddressOf = (mint: PublicKey) =>
+    findAddress(
+      [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), mint.toBuffer()],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+
+  const editionAddressOf = (mint: PublicKey) =>
+    findAddress(
+      [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), mint.toBuffer(), Buffer.from("edition")],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+
+  const tokenTypeConfigOf = (tokenType: number) =>
+    findAddress([Buffer.from("tokenType"), upgradeWeaponAccount.publicKey.toBuffer(), Buffer.from([tokenType])]);
+
+  const weaponAddressOf = (mint: PublicKey) => findAddress([Buffer.from("weapon"), mint.toBuffer()]);
+
+  const mintRecordOf = (mint: PublicKey) => findAddress([Buffer.from("mintRecord"), mint.toBuffer()]);
+
+  // The wallet's counter of `tokenType` mints, or of those within `phase`.
+  const mintCounterOf = (wallet: PublicKey, tokenType: number, phase?: number) =>
+    findAddress([
+      Buffer.from("mintCounter"),
+      upgradeWeaponAccount.publicKey.toBuffer(),
+      wallet.toBuffer(),
+      Buffer.from([tokenType]),
+      ...(phase === undefined ? [] : [Buffer.from([phase])]),
+    ]);
+
+  const tokenAccountOf = (mint: PublicKey, owner: PublicKey) =>
+    anchor.utils.token.associatedAddress({ mint, owner });
+
+  const freezeAuthorityAddress = () => findAddress([Buffer.from("freezeAuthority")]);
+
+  const lamportsOf = async (address: PublicKey) => provider.connection.getBalance(address);
+
+  // Sends `lamports` from the provider wallet. Anchor.toml targets devnet,
+  // where faucet airdrops are too rate limited to fund every test wallet.
+  const fund = async (wallet: PublicKey, lamports: number) => {
+    await provider.sendAndConfirm(
+      new Transaction().add(
+        SystemProgram.transfer({ fromPubkey: authority.publicKey, toPubkey: wallet, lamports })
+      )
+    );
+  };
+
+  // Calls one of the admin setters sharing the `SetTokenTypeConfig` accounts.
+  const setTokenTypeConfig = async (method: string, tokenType: number, ...args: any[]) => {
+    await program.methods[method](tokenType, ...args)
+      .accounts({
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        tokenTypeConfig: await tokenTypeConfigOf(tokenType),
+        authority: authority.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([authority.payer])
+      .rpc();
+  };
+
+  // Mints a weapon of a paid `tokenType` to `minter` through `mint`, with the
+  // admin co-signing as the backend does. Paid types need no device
+  // attestation, so the device is left blank.
+  const mintWeapon = async (tokenType: number, minter: Keypair = authority.payer, phase = 0, proof: number[][] = []) => {
+    const mint = Keypair.generate();
+    const device = { tokenType, deviceId: "", expiresAt: new BN(0) };
+
+    await program.methods
+      .mint(tokenType, device, phase, proof, Array.from(Keypair.generate().publicKey.toBytes()))
+      .accounts({
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        mint: mint.publicKey,
+        authority: minter.publicKey,
+        owner: authority.publicKey,
+        tokenAccount: await tokenAccountOf(mint.publicKey, minter.publicKey),
+        metadataAccount: await metadataAddressOf(mint.publicKey),
+        weaponAccount: await weaponAddressOf(mint.publicKey),
+        mintRecord: await mintRecordOf(mint.publicKey),
+        edition: await editionAddressOf(mint.publicKey),
+        collection: await metadataAddressOf(weaponCollection),
+        collectionMint: weaponCollection,
+        collectionMasterEdition: await editionAddressOf(weaponCollection),
+        nftAccount: await findAddress([Buffer.from("mintedNFT"), minter.publicKey.toBuffer()]),
+        tokenTypeConfig: await tokenTypeConfigOf(tokenType),
+        mintCounter: await mintCounterOf(minter.publicKey, tokenType),
+        phaseCounter: await mintCounterOf(minter.publicKey, tokenType, phase),
+        deviceAccount: await findAddress([Buffer.from("device"), createHash("sha256").update(device.deviceId).digest()]),
+        freezeAuthority: await freezeAuthorityAddress(),
+        instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+        rent: SYSVAR_RENT_PUBKEY,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      })
+      .preInstructions([ComputeBudgetProgram.setComputeUnitLimit({ units: 400000 })])
+      .signers([mint, minter, authority.payer])
+      .rpc();
+
+    return mint.publicKey;
+  };
+
+  // Mints a weapon of `tokenType` to `recipient` through the admin airdrop.
+  const airdropWeapon = async (recipient: PublicKey, tokenType: number) => {
+    const mint = Keypair.generate();
+
+    await program.methods
+      .airdrop(tokenType, Array.from(Keypair.generate().publicKey.toBytes()))
+      .accounts({
+        mint: mint.publicKey,
+        tokenAccount: await tokenAccountOf(mint.publicKey, recipient),
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        authority: authority.publicKey,
+        recipient: recipient,
+        metadataAccount: await metadataAddressOf(mint.publicKey),
+        weaponAccount: await weaponAddressOf(mint.publicKey),
+        mintRecord: await mintRecordOf(mint.publicKey),
+        edition: await editionAddressOf(mint.publicKey),
+        collection: await metadataAddressOf(weaponCollection),
+        collectionMint: weaponCollection,
+        collectionMasterEdition: await editionAddressOf(weaponCollection),
+        tokenTypeConfig: await tokenTypeConfigOf(tokenType),
+        rent: SYSVAR_RENT_PUBKEY,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      })
+      .preInstructions([ComputeBudgetProgram.setComputeUnitLimit({ units: 400000 })])
+      .signers([mint, authority.payer])
+      .rpc();
+
+    return mint.publicKey;
+  };
+
+  // Sets level, hp, damage, mana, mp regen and attack speed of a weapon held
+  // by `owner`, as the game server does after a direct upgrade.
+  const upgradeStats = async (owner: Keypair, mint: PublicKey, tokenType: number, stats: number[]) => {
+    await program.methods
+      .upgradeWeaponLevel(stats.map((stat) => new BN(stat)))
+      .accounts({
+        owner: owner.publicKey,
+        weaponAccount: await weaponAddressOf(mint),
+        tokenAccount: await tokenAccountOf(mint, owner.publicKey),
+        authority: authority.publicKey,
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        mintRecord: await mintRecordOf(mint),
+        tokenTypeConfig: await tokenTypeConfigOf(tokenType),
+        upgradeCounter: await findAddress([
+          Buffer.from("upgradeCounter"),
+          upgradeWeaponAccount.publicKey.toBuffer(),
+          owner.publicKey.toBuffer(),
+          Buffer.from([tokenType]),
+        ]),
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([owner, authority.payer])
+      .rpc();
+  };
+
+  const grantXp = async (mint: PublicKey, tokenType: number, amount: number) => {
+    await program.methods
+      .grantXp(new BN(amount))
+      .accounts({
+        authority: authority.publicKey,
+        weaponAccount: await weaponAddressOf(mint),
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        mintRecord: await mintRecordOf(mint),
+        tokenTypeConfig: await tokenTypeConfigOf(tokenType),
+      })
+      .signers([authority.payer])
+      .rpc();
+  };
+
+  // The cluster's clock, which offer and rental expiries are checked against.
+  const chainTime = async () => {
+    const clock = await provider.connection.getAccountInfo(anchor.web3.SYSVAR_CLOCK_PUBKEY);
+    return Number(clock.data.readBigInt64LE(32));
+  };
+
+  const waitUntil = async (timestamp: number) => {
+    while ((await chainTime()) < timestamp) {
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+    }
+  };
+
+  // Program errors are plain `ProgramError::Custom` codes, so they surface as
+  // "custom program error: 0x.." in the failed transaction's message.
+  const assertProgramError = async (request: Promise<unknown>, code: number) => {
+    try {
+      await request;
+    } catch (e) {
+      assert.include(String(e), `custom program error: 0x${code.toString(16)}`);
+      return;
+    }
+
+    assert.fail(`expected custom program error ${code}`);
+  };
+
   it("should initialize the UpgradeWeapon", async () => {
     await program.rpc.initialize("Arcane Arsenal", "AA", {
       accounts: {
@@ -103,6 +309,30 @@ describe("UpgradeWeapon", () => {
   });
 
 
+  it("should set mint limits", async () => {
+    const tokenTypeConfig = (
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("tokenType"), upgradeWeaponAccount.publicKey.toBuffer(), Buffer.from([4])],
+        new PublicKey("C7KQdF6atRDnJe9cCLomcESLFZCtYa9SEpRT5i9Y4J3u")
+      )
+    )[0];
+
+    await program.methods
+      .setMintLimits(4, 1, 0, new BN(0))
+      .accounts({
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        tokenTypeConfig: tokenTypeConfig,
+        authority: authority.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([authority.payer])
+      .rpc();
+
+    const config = await program.account.tokenTypeConfig.fetch(tokenTypeConfig);
+
+    assert.ok(config.walletLimit === 1);
+  });
+
   it("Mint a token", async () => {  
     const tokenAddress = await anchor.utils.token.associatedAddress({
       mint: mintKey.publicKey,
@@ -116,6 +346,38 @@ describe("UpgradeWeapon", () => {
       )
     )[0];
 
+    const tokenTypeConfig = (
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("tokenType"), upgradeWeaponAccount.publicKey.toBuffer(), Buffer.from([4])],
+        new PublicKey("C7KQdF6atRDnJe9cCLomcESLFZCtYa9SEpRT5i9Y4J3u")
+      )
+    )[0];
+
+    const mintCounter = (
+      await anchor.web3.PublicKey.findProgramAddress(
+        [
+          Buffer.from("mintCounter"),
+          upgradeWeaponAccount.publicKey.toBuffer(),
+          authority.publicKey.toBuffer(),
+          Buffer.from([4]),
+        ],
+        new PublicKey("C7KQdF6atRDnJe9cCLomcESLFZCtYa9SEpRT5i9Y4J3u")
+      )
+    )[0];
+
+    const phaseCounter = (
+      await anchor.web3.PublicKey.findProgramAddress(
+        [
+          Buffer.from("mintCounter"),
+          upgradeWeaponAccount.publicKey.toBuffer(),
+          authority.publicKey.toBuffer(),
+          Buffer.from([4]),
+          Buffer.from([0]),
+        ],
+        new PublicKey("C7KQdF6atRDnJe9cCLomcESLFZCtYa9SEpRT5i9Y4J3u")
+      )
+    )[0];
+
     console.log('===============nftAccount====================');
     console.log(nftAccount);
     console.log('===================================');
@@ -151,6 +413,13 @@ describe("UpgradeWeapon", () => {
       )
     )[0];
 
+    const mintRecord = (
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("mintRecord"), mintKey.publicKey.toBuffer()],
+        new PublicKey("C7KQdF6atRDnJe9cCLomcESLFZCtYa9SEpRT5i9Y4J3u")
+      )
+    )[0];
+
     try{
 
 
@@ -185,8 +454,42 @@ describe("UpgradeWeapon", () => {
       )[0];
 
 
+      const device = {
+        tokenType: 4,
+        deviceId: "test-device",
+        expiresAt: new BN(Math.floor(Date.now() / 1000) + 300),
+      };
+      const deviceHash = createHash("sha256").update(device.deviceId).digest();
+
+      const deviceAccount = (
+        await anchor.web3.PublicKey.findProgramAddress(
+          [Buffer.from("device"), deviceHash],
+          new PublicKey("C7KQdF6atRDnJe9cCLomcESLFZCtYa9SEpRT5i9Y4J3u")
+        )
+      )[0];
+
+      const deviceAttestation = Ed25519Program.createInstructionWithPrivateKey({
+        privateKey: authority.payer.secretKey,
+        message: Buffer.concat([
+          authority.publicKey.toBuffer(),
+          Buffer.from([device.tokenType]),
+          deviceHash,
+          device.expiresAt.toArrayLike(Buffer, "le", 8),
+        ]),
+      });
+
+      // Type 4 has no rarity tiers, so the seed goes unsigned and unused.
+      const raritySeed = Array.from(Keypair.generate().publicKey.toBytes());
+
+      const freezeAuthority = (
+        await anchor.web3.PublicKey.findProgramAddress(
+          [Buffer.from("freezeAuthority")],
+          new PublicKey("C7KQdF6atRDnJe9cCLomcESLFZCtYa9SEpRT5i9Y4J3u")
+        )
+      )[0];
+
       const txSig = await program.methods.
-      mint(new BN(4))
+      mint(4, device, 0, [], raritySeed)
       .accounts({
         upgradeWeapon: upgradeWeaponAccount.publicKey,
         mint: mintKey.publicKey,
@@ -194,6 +497,7 @@ describe("UpgradeWeapon", () => {
         authority: authority.publicKey,
         metadataAccount: metadataAddress,
         weaponAccount: weaponAddress,
+        mintRecord: mintRecord,
         tokenAccount: tokenAddress,
         tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
         edition: metadataEditionAddress,
@@ -202,8 +506,14 @@ describe("UpgradeWeapon", () => {
         collection: collectionMetadata,
         collectionMasterEdition: collectionEdition,
         nftAccount: nftAccount,
+        tokenTypeConfig: tokenTypeConfig,
+        mintCounter: mintCounter,
+        phaseCounter: phaseCounter,
+        deviceAccount: deviceAccount,
+        freezeAuthority: freezeAuthority,
+        instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
       })
-      .preInstructions([additionalComputeBudgetInstruction])
+      .preInstructions([additionalComputeBudgetInstruction, deviceAttestation])
       .signers([mintKey, authority.payer])
       .rpc();
 
@@ -231,6 +541,32 @@ describe("UpgradeWeapon", () => {
       )
     )[0];
 
+    const mintRecord = (
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("mintRecord"), mintKey.publicKey.toBuffer()],
+        new PublicKey("C7KQdF6atRDnJe9cCLomcESLFZCtYa9SEpRT5i9Y4J3u")
+      )
+    )[0];
+
+    const tokenTypeConfig = (
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("tokenType"), upgradeWeaponAccount.publicKey.toBuffer(), Buffer.from([4])],
+        new PublicKey("C7KQdF6atRDnJe9cCLomcESLFZCtYa9SEpRT5i9Y4J3u")
+      )
+    )[0];
+
+    const upgradeCounter = (
+      await anchor.web3.PublicKey.findProgramAddress(
+        [
+          Buffer.from("upgradeCounter"),
+          upgradeWeaponAccount.publicKey.toBuffer(),
+          authority.publicKey.toBuffer(),
+          Buffer.from([4]),
+        ],
+        new PublicKey("C7KQdF6atRDnJe9cCLomcESLFZCtYa9SEpRT5i9Y4J3u")
+      )
+    )[0];
+
     await program.methods
       .upgradeWeaponLevel([
         new BN(20),
@@ -244,6 +580,12 @@ describe("UpgradeWeapon", () => {
         owner: authority.publicKey,
         weaponAccount: weaponAddress,
         tokenAccount: tokenAddress,
+        authority: authority.publicKey,
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        mintRecord: mintRecord,
+        tokenTypeConfig: tokenTypeConfig,
+        upgradeCounter: upgradeCounter,
+        systemProgram: SystemProgram.programId,
       })
       .signers([authority.payer])
       .rpc();
@@ -261,6 +603,21 @@ describe("UpgradeWeapon", () => {
       borsh.u64("mana"),
       borsh.u64("mpRegen"),
       borsh.u64("atkSpeed"),
+      borsh.u8("version"),
+      borsh.publicKey("mint"),
+      borsh.u64("statNonce"),
+      borsh.publicKey("holder"),
+      borsh.i64("lastUpgradedAt"),
+      borsh.publicKey("equippedTo"),
+      borsh.u64("wear"),
+      borsh.bool("broken"),
+      borsh.u64("xp"),
+      borsh.i64("xpDay"),
+      borsh.u64("xpToday"),
+      borsh.u8("rarity"),
+      borsh.array(borsh.u64(), 5, "baseStats"),
+      borsh.array(borsh.publicKey(), 3, "gems"),
+      borsh.array(borsh.array(borsh.u64(), 5), 3, "gemBonuses"),
     ]);
 
     const deseralizedInfo = borshAccountSchema.decode(
@@ -273,4 +630,1181 @@ describe("UpgradeWeapon", () => {
 
     assert.ok(deseralizedInfo.hp === 20);
   });
+
+  // Upgrades the `mintKey` weapon with stats the backend signed, verified
+  // through an Ed25519 instruction placed before the upgrade.
+  const signedUpgrade = async (
+    stats: number[],
+    nonce: number,
+    expiresAt: number,
+    signedStats: number[] = stats,
+    signer: Keypair = authority.payer
+  ) => {
+    const weaponAddress = await weaponAddressOf(mintKey.publicKey);
+    const message = Buffer.concat([
+      weaponAddress.toBuffer(),
+      ...signedStats.map((stat) => new BN(stat).toArrayLike(Buffer, "le", 8)),
+      new BN(nonce).toArrayLike(Buffer, "le", 8),
+      new BN(expiresAt).toArrayLike(Buffer, "le", 8),
+    ]);
+
+    await program.methods
+      .upgradeWeaponLevelSigned(stats.map((stat) => new BN(stat)), new BN(nonce), new BN(expiresAt))
+      .accounts({
+        owner: authority.publicKey,
+        weaponAccount: weaponAddress,
+        tokenAccount: await tokenAccountOf(mintKey.publicKey, authority.publicKey),
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        mintRecord: await mintRecordOf(mintKey.publicKey),
+        tokenTypeConfig: await tokenTypeConfigOf(4),
+        upgradeCounter: await findAddress([
+          Buffer.from("upgradeCounter"),
+          upgradeWeaponAccount.publicKey.toBuffer(),
+          authority.publicKey.toBuffer(),
+          Buffer.from([4]),
+        ]),
+        systemProgram: SystemProgram.programId,
+        instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+      })
+      .preInstructions([Ed25519Program.createInstructionWithPrivateKey({ privateKey: signer.secretKey, message })])
+      .signers([authority.payer])
+      .rpc();
+  };
+
+  it("should apply a server-signed upgrade once", async () => {
+    const stats = [3, 30, 31, 32, 33, 34];
+    const expiresAt = (await chainTime()) + 600;
+
+    await signedUpgrade(stats, 1, expiresAt);
+
+    const weapon = await program.account.weapon.fetch(await weaponAddressOf(mintKey.publicKey));
+
+    assert.equal(weapon.level.toNumber(), 3);
+    assert.equal(weapon.statNonce.toNumber(), 1);
+
+    // The same signed upgrade can't be replayed once its nonce is used.
+    await assertProgramError(signedUpgrade(stats, 1, expiresAt), 11);
+  });
+
+  it("should refuse expired or wrongly signed upgrades", async () => {
+    const stats = [4, 40, 41, 42, 43, 44];
+    const now = await chainTime();
+
+    await assertProgramError(signedUpgrade(stats, 2, now - 60), 10);
+    await assertProgramError(signedUpgrade(stats, 2, now + 600, stats, Keypair.generate()), 6);
+    await assertProgramError(signedUpgrade(stats, 2, now + 600, [9, 90, 91, 92, 93, 94]), 6);
+  });
+
+  const leafOf = (wallet: PublicKey) => Buffer.from(keccak_256.arrayBuffer(wallet.toBuffer()));
+
+  // Pairs are hashed in sorted order, as `verify_merkle_proof` does.
+  const parentOf = (a: Buffer, b: Buffer) =>
+    Buffer.from(keccak_256.arrayBuffer(Buffer.compare(a, b) <= 0 ? Buffer.concat([a, b]) : Buffer.concat([b, a])));
+
+  it("should only mint allowlisted wallets within their phase allocation", async () => {
+    const authorityLeaf = leafOf(authority.publicKey);
+    const otherLeaf = leafOf(Keypair.generate().publicKey);
+    const proof = [Array.from(otherLeaf)];
+    const now = await chainTime();
+
+    await setTokenTypeConfig("setMintPhases", 2, [
+      {
+        start: new BN(now - 60),
+        end: new BN(now + 3600),
+        merkleRoot: Array.from(parentOf(authorityLeaf, otherLeaf)),
+        price: new BN(1_000),
+        walletAllocation: 1,
+      },
+    ]);
+
+    await assertProgramError(mintWeapon(2), 17);
+
+    const mint = await mintWeapon(2, authority.payer, 0, proof);
+    const weapon = await program.account.weapon.fetch(await weaponAddressOf(mint));
+
+    assert.ok(weapon.mint.equals(mint));
+
+    await assertProgramError(mintWeapon(2, authority.payer, 0, proof), 18);
+
+    await setTokenTypeConfig("setMintPhases", 2, []);
+  });
+
+  it("should only mint within the token type's mint window", async () => {
+    const now = await chainTime();
+
+    await setTokenTypeConfig("setMintWindow", 3, new BN(now + 3600), null);
+    await assertProgramError(mintWeapon(3), 20);
+
+    await setTokenTypeConfig("setMintWindow", 3, new BN(now - 3600), new BN(now - 60));
+    await assertProgramError(mintWeapon(3), 21);
+
+    await setTokenTypeConfig("setMintWindow", 3, new BN(now - 3600), new BN(now + 3600));
+    await mintWeapon(3);
+
+    await setTokenTypeConfig("setMintWindow", 3, null, null);
+  });
+
+  // The accounts `mint_batch` reads for each of `mints`, in order.
+  const batchAccountsOf = async (mints: Keypair[], tokenTypes: number[]) => {
+    const accounts = [];
+
+    for (let i = 0; i < mints.length; i++) {
+      const mint = mints[i].publicKey;
+      const addresses = [
+        mint,
+        await tokenAccountOf(mint, authority.publicKey),
+        await metadataAddressOf(mint),
+        await editionAddressOf(mint),
+        await weaponAddressOf(mint),
+        await tokenTypeConfigOf(tokenTypes[i]),
+        await mintCounterOf(authority.publicKey, tokenTypes[i]),
+        await mintCounterOf(authority.publicKey, tokenTypes[i], 0),
+        await mintRecordOf(mint),
+      ];
+
+      addresses.forEach((pubkey, j) => accounts.push({ pubkey, isWritable: true, isSigner: j === 0 }));
+    }
+
+    return accounts;
+  };
+
+  const mintBatch = async (tokenTypes: number[], mints: Keypair[] = tokenTypes.map(() => Keypair.generate())) => {
+    const proofs = tokenTypes.map(() => []);
+
+    await program.methods
+      .mintBatch(tokenTypes, 0, proofs, tokenTypes.map(() => Array.from(Keypair.generate().publicKey.toBytes())))
+      .accounts({
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        authority: authority.publicKey,
+        owner: authority.publicKey,
+        collection: await metadataAddressOf(weaponCollection),
+        collectionMint: weaponCollection,
+        collectionMasterEdition: await editionAddressOf(weaponCollection),
+        nftAccount: await findAddress([Buffer.from("mintedNFT"), authority.publicKey.toBuffer()]),
+        freezeAuthority: await freezeAuthorityAddress(),
+        instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+        rent: SYSVAR_RENT_PUBKEY,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      })
+      .remainingAccounts(await batchAccountsOf(mints, tokenTypes))
+      .preInstructions([ComputeBudgetProgram.setComputeUnitLimit({ units: 1_400_000 })])
+      .signers([...mints, authority.payer])
+      .rpc();
+
+    return mints.map((mint) => mint.publicKey);
+  };
+
+  it("should mint a batch of paid weapons", async () => {
+    const [mint] = await mintBatch([3]);
+    const weapon = await program.account.weapon.fetch(await weaponAddressOf(mint));
+
+    assert.ok(weapon.mint.equals(mint));
+  });
+
+  it("should refuse oversized batches and free types in a batch", async () => {
+    await assertProgramError(mintBatch([3, 3, 3, 3], []), 22);
+    await assertProgramError(mintBatch([4]), 24);
+  });
+
+  const voucherAddressOf = (id: number) =>
+    findAddress([
+      Buffer.from("voucher"),
+      upgradeWeaponAccount.publicKey.toBuffer(),
+      new BN(id).toArrayLike(Buffer, "le", 8),
+    ]);
+
+  const createVoucher = async (id: number, tokenType: number, recipient: PublicKey | null, expiresAt: number | null, uses: number) => {
+    await program.methods
+      .createVoucher(new BN(id), tokenType, recipient, expiresAt === null ? null : new BN(expiresAt), uses)
+      .accounts({
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        voucher: await voucherAddressOf(id),
+        authority: authority.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([authority.payer])
+      .rpc();
+  };
+
+  // Redeems voucher `id` for the provider wallet, which also co-signs as the
+  // admin.
+  const redeemVoucher = async (id: number, tokenType: number) => {
+    const mint = Keypair.generate();
+    const voucher = await voucherAddressOf(id);
+
+    await program.methods
+      .redeemVoucher(new BN(id), Array.from(Keypair.generate().publicKey.toBytes()))
+      .accounts({
+        voucher: voucher,
+        redemption: await findAddress([Buffer.from("voucherRedemption"), voucher.toBuffer(), authority.publicKey.toBuffer()]),
+        mint: mint.publicKey,
+        tokenAccount: await tokenAccountOf(mint.publicKey, authority.publicKey),
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        authority: authority.publicKey,
+        owner: authority.publicKey,
+        metadataAccount: await metadataAddressOf(mint.publicKey),
+        weaponAccount: await weaponAddressOf(mint.publicKey),
+        mintRecord: await mintRecordOf(mint.publicKey),
+        edition: await editionAddressOf(mint.publicKey),
+        collection: await metadataAddressOf(weaponCollection),
+        collectionMint: weaponCollection,
+        collectionMasterEdition: await editionAddressOf(weaponCollection),
+        tokenTypeConfig: await tokenTypeConfigOf(tokenType),
+        freezeAuthority: await freezeAuthorityAddress(),
+        instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+        rent: SYSVAR_RENT_PUBKEY,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      })
+      .preInstructions([ComputeBudgetProgram.setComputeUnitLimit({ units: 400000 })])
+      .signers([mint, authority.payer])
+      .rpc();
+
+    return mint.publicKey;
+  };
+
+  it("should redeem a voucher and close it after its last use", async () => {
+    await assertProgramError(createVoucher(1, 3, Keypair.generate().publicKey, null, 2), 25);
+
+    await createVoucher(1, 3, null, null, 1);
+
+    const mint = await redeemVoucher(1, 3);
+    const record = await program.account.mintRecord.fetch(await mintRecordOf(mint));
+
+    assert.deepEqual(record.source, { voucher: {} });
+    assert.isNull(await provider.connection.getAccountInfo(await voucherAddressOf(1)));
+  });
+
+  it("should refuse expired vouchers", async () => {
+    const expiresAt = (await chainTime()) + 5;
+
+    await createVoucher(2, 3, null, expiresAt, 1);
+    await waitUntil(expiresAt);
+
+    await assertProgramError(redeemVoucher(2, 3), 26);
+  });
+
+  it("should refund a mint's price once", async () => {
+    const minter = Keypair.generate();
+
+    await fund(minter.publicKey, 300_000_000);
+
+    const mint = await mintWeapon(3, minter);
+    const mintRecord = await mintRecordOf(mint);
+    const { price } = await program.account.mintRecord.fetch(mintRecord);
+
+    const refund = () =>
+      program.methods
+        .refund()
+        .accounts({
+          authority: authority.publicKey,
+          mint: mint,
+          mintRecord: mintRecord,
+          minter: minter.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([authority.payer])
+        .rpc();
+
+    const minterBefore = await lamportsOf(minter.publicKey);
+
+    await refund();
+
+    assert.equal(price.toNumber(), 100_000_000);
+    assert.equal((await lamportsOf(minter.publicKey)) - minterBefore, price.toNumber());
+    assert.equal((await program.account.mintRecord.fetch(mintRecord)).price.toNumber(), 0);
+
+    await assertProgramError(refund(), 27);
+  });
+
+  // Burns a weapon `holder` holds, signed by `signer`.
+  const burnWeapon = async (signer: Keypair, mint: PublicKey, tokenType: number, holder: PublicKey = signer.publicKey) => {
+    await program.methods
+      .burn()
+      .accounts({
+        metadataAccount: await metadataAddressOf(mint),
+        authority: signer.publicKey,
+        mint: mint,
+        associationTokenAccount: await tokenAccountOf(mint, holder),
+        metadataEditionAccount: await editionAddressOf(mint),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        weaponAccount: await weaponAddressOf(mint),
+        mintRecord: await mintRecordOf(mint),
+        tokenTypeConfig: await tokenTypeConfigOf(tokenType),
+        mintCounter: await mintCounterOf(holder, tokenType),
+      })
+      .signers([signer])
+      .rpc();
+  };
+
+  it("should only burn held weapons of the collection", async () => {
+    const mint = await airdropWeapon(authority.publicKey, 3);
+
+    await assertProgramError(burnWeapon(authority.payer, mint, 3), 28);
+
+    await program.methods
+      .setCollection(weaponCollection)
+      .accounts({
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        authority: authority.publicKey,
+      })
+      .signers([authority.payer])
+      .rpc();
+
+    await assertProgramError(burnWeapon(Keypair.generate(), mint, 3, authority.publicKey), 3);
+
+    await burnWeapon(authority.payer, mint, 3);
+
+    assert.isNull(await provider.connection.getAccountInfo(await weaponAddressOf(mint)));
+  });
+
+  it("should close a burned weapon's accounts and lower the live supply", async () => {
+    const mint = await airdropWeapon(authority.publicKey, 3);
+    const supplyBefore = (await program.account.upgradeWeapon.fetch(upgradeWeaponAccount.publicKey)).liveSupply[3];
+
+    await burnWeapon(authority.payer, mint, 3);
+
+    const { liveSupply } = await program.account.upgradeWeapon.fetch(upgradeWeaponAccount.publicKey);
+
+    assert.equal(supplyBefore.toNumber() - liveSupply[3].toNumber(), 1);
+    assert.isNull(await provider.connection.getAccountInfo(await weaponAddressOf(mint)));
+    assert.isNull(await provider.connection.getAccountInfo(await mintRecordOf(mint)));
+  });
+
+  const transferWeapon = async (from: Keypair, to: PublicKey, mint: PublicKey, tokenType: number) => {
+    await program.methods
+      .transfer()
+      .accounts({
+        from: from.publicKey,
+        fromAssociationTokenAccount: await tokenAccountOf(mint, from.publicKey),
+        mint: mint,
+        to: to,
+        toAssociationTokenAccount: await tokenAccountOf(mint, to),
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        metadataAccount: await metadataAddressOf(mint),
+        weaponAccount: await weaponAddressOf(mint),
+        mintRecord: await mintRecordOf(mint),
+        tokenTypeConfig: await tokenTypeConfigOf(tokenType),
+        owner: authority.publicKey,
+        systemProgram: SystemProgram.programId,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([from])
+      .rpc();
+  };
+
+  it("should charge transfer fees and enforce the type's transfer rules", async () => {
+    const trader = Keypair.generate();
+    const receiver = Keypair.generate();
+
+    await fund(trader.publicKey, 100_000_000);
+    await fund(receiver.publicKey, 100_000_000);
+    await setTokenTypeConfig("setTransferRules", 1, false, new BN(3600), new BN(10_000));
+
+    const mint = await airdropWeapon(trader.publicKey, 1);
+    const traderBefore = await lamportsOf(trader.publicKey);
+
+    await transferWeapon(trader, receiver.publicKey, mint, 1);
+
+    // The sender also pays for the receiver's token account.
+    const tokenAccountRent = await provider.connection.getMinimumBalanceForRentExemption(165);
+
+    assert.equal(traderBefore - (await lamportsOf(trader.publicKey)), 10_000 + tokenAccountRent);
+
+    // Upgrading starts the transfer cooldown.
+    await upgradeStats(receiver, mint, 1, [1, 1, 1, 1, 1, 1]);
+    await assertProgramError(transferWeapon(receiver, trader.publicKey, mint, 1), 30);
+
+    await setTokenTypeConfig("setTransferRules", 1, true, new BN(0), new BN(0));
+    await assertProgramError(transferWeapon(receiver, trader.publicKey, mint, 1), 29);
+
+    await setTokenTypeConfig("setTransferRules", 1, false, new BN(0), new BN(0));
+  });
+
+  const unbind = async (mint: PublicKey, tokenType: number) => {
+    await program.methods
+      .unbind()
+      .accounts({
+        authority: authority.publicKey,
+        mint: mint,
+        tokenAccount: await tokenAccountOf(mint, authority.publicKey),
+        edition: await editionAddressOf(mint),
+        freezeAuthority: await freezeAuthorityAddress(),
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        weaponAccount: await weaponAddressOf(mint),
+        mintRecord: await mintRecordOf(mint),
+        tokenTypeConfig: await tokenTypeConfigOf(tokenType),
+        stakeAccount: await findAddress([Buffer.from("stake"), mint.toBuffer()]),
+        rental: await findAddress([Buffer.from("rental"), mint.toBuffer()]),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+      })
+      .signers([authority.payer])
+      .rpc();
+  };
+
+  it("should freeze soulbound weapons until the admin unbinds them", async () => {
+    await setTokenTypeConfig("setTransferRules", 3, true, new BN(0), new BN(0));
+
+    const mint = await mintWeapon(3);
+    const tokenAccount = await tokenAccountOf(mint, authority.publicKey);
+
+    assert.ok((await getAccount(provider.connection, tokenAccount)).isFrozen);
+
+    await unbind(mint, 3);
+
+    assert.ok(!(await getAccount(provider.connection, tokenAccount)).isFrozen);
+
+    // Only weapons of soulbound types can be unbound.
+    await setTokenTypeConfig("setTransferRules", 3, false, new BN(0), new BN(0));
+    await assertProgramError(unbind(mint, 3), 40);
+  });
+
+  const seller = Keypair.generate();
+  const buyer = Keypair.generate();
+  const royaltyRecipient = Keypair.generate();
+
+  const marketConfigAddress = () =>
+    findAddress([Buffer.from("market"), upgradeWeaponAccount.publicKey.toBuffer()]);
+
+  it("should set the collection and market config", async () => {
+    await program.methods
+      .setCollection(weaponCollection)
+      .accounts({
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        authority: authority.publicKey,
+      })
+      .signers([authority.payer])
+      .rpc();
+
+    await program.methods
+      .setMarketConfig(500, royaltyRecipient.publicKey, 250)
+      .accounts({
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        marketConfig: await marketConfigAddress(),
+        authority: authority.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([authority.payer])
+      .rpc();
+
+    // Type 1 is the tradeable type of these tests, with a transfer fee on top
+    // of the market's protocol fee.
+    await setTokenTypeConfig("setTransferRules", 1, false, new BN(0), new BN(10_000));
+
+    await fund(seller.publicKey, 300_000_000);
+    await fund(buyer.publicKey, 400_000_000);
+
+    const config = await program.account.marketConfig.fetch(await marketConfigAddress());
+
+    assert.ok(config.royaltyBps === 500);
+    assert.ok(config.protocolFeeBps === 250);
+  });
+
+  it("should split a sale between royalty, treasury and seller", async () => {
+    const mint = await airdropWeapon(seller.publicKey, 1);
+    const listing = await findAddress([Buffer.from("listing"), mint.toBuffer()]);
+    const escrow = await findAddress([Buffer.from("escrow"), mint.toBuffer()]);
+    const price = 100_000_000;
+
+    await program.methods
+      .list(new BN(price))
+      .accounts({
+        seller: seller.publicKey,
+        mint: mint,
+        sellerTokenAccount: await tokenAccountOf(mint, seller.publicKey),
+        listing: listing,
+        escrow: escrow,
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        metadataAccount: await metadataAddressOf(mint),
+        weaponAccount: await weaponAddressOf(mint),
+        mintRecord: await mintRecordOf(mint),
+        tokenTypeConfig: await tokenTypeConfigOf(1),
+        rent: SYSVAR_RENT_PUBKEY,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([seller])
+      .rpc();
+
+    // The listing and escrow rent goes back to the seller on top of the sale.
+    const rentBack = (await lamportsOf(listing)) + (await lamportsOf(escrow));
+    const sellerBefore = await lamportsOf(seller.publicKey);
+    const royaltyBefore = await lamportsOf(royaltyRecipient.publicKey);
+
+    await assertProgramError(
+      program.methods
+        .buy(new BN(price + 1))
+        .accounts({
+          buyer: buyer.publicKey,
+          seller: seller.publicKey,
+          mint: mint,
+          buyerTokenAccount: await tokenAccountOf(mint, buyer.publicKey),
+          listing: listing,
+          escrow: escrow,
+          upgradeWeapon: upgradeWeaponAccount.publicKey,
+          weaponAccount: await weaponAddressOf(mint),
+          mintRecord: await mintRecordOf(mint),
+          tokenTypeConfig: await tokenTypeConfigOf(1),
+          marketConfig: await marketConfigAddress(),
+          royaltyRecipient: royaltyRecipient.publicKey,
+          owner: authority.publicKey,
+          systemProgram: SystemProgram.programId,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([buyer])
+        .rpc(),
+      33
+    );
+
+    await program.methods
+      .buy(new BN(price))
+      .accounts({
+        buyer: buyer.publicKey,
+        seller: seller.publicKey,
+        mint: mint,
+        buyerTokenAccount: await tokenAccountOf(mint, buyer.publicKey),
+        listing: listing,
+        escrow: escrow,
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        weaponAccount: await weaponAddressOf(mint),
+        mintRecord: await mintRecordOf(mint),
+        tokenTypeConfig: await tokenTypeConfigOf(1),
+        marketConfig: await marketConfigAddress(),
+        royaltyRecipient: royaltyRecipient.publicKey,
+        owner: authority.publicKey,
+        systemProgram: SystemProgram.programId,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([buyer])
+      .rpc();
+
+    // 5% royalty, and 2.5% protocol fee plus the 10 000 lamport transfer fee
+    // for the treasury.
+    const royalty = 5_000_000;
+    const treasury = 2_500_000 + 10_000;
+
+    assert.equal((await lamportsOf(royaltyRecipient.publicKey)) - royaltyBefore, royalty);
+    assert.equal((await lamportsOf(seller.publicKey)) - sellerBefore, price - royalty - treasury + rentBack);
+
+    const buyerTokenAccount = await getAccount(provider.connection, await tokenAccountOf(mint, buyer.publicKey));
+    const weapon = await program.account.weapon.fetch(await weaponAddressOf(mint));
+
+    assert.equal(Number(buyerTokenAccount.amount), 1);
+    assert.ok(weapon.holder.equals(buyer.publicKey));
+    assert.isNull(await provider.connection.getAccountInfo(listing));
+  });
+
+  const offerAddressOf = (bidder: PublicKey, id: number) =>
+    findAddress([
+      Buffer.from("offer"),
+      upgradeWeaponAccount.publicKey.toBuffer(),
+      bidder.toBuffer(),
+      new BN(id).toArrayLike(Buffer, "le", 8),
+    ]);
+
+  const acceptOfferAccounts = async (mint: PublicKey, offer: PublicKey) => ({
+    seller: seller.publicKey,
+    sellerTokenAccount: await tokenAccountOf(mint, seller.publicKey),
+    bidder: buyer.publicKey,
+    bidderTokenAccount: await tokenAccountOf(mint, buyer.publicKey),
+    offer: offer,
+    mint: mint,
+    upgradeWeapon: upgradeWeaponAccount.publicKey,
+    metadataAccount: await metadataAddressOf(mint),
+    weaponAccount: await weaponAddressOf(mint),
+    mintRecord: await mintRecordOf(mint),
+    tokenTypeConfig: await tokenTypeConfigOf(1),
+    marketConfig: await marketConfigAddress(),
+    royaltyRecipient: royaltyRecipient.publicKey,
+    owner: authority.publicKey,
+    systemProgram: SystemProgram.programId,
+    associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+    tokenProgram: TOKEN_PROGRAM_ID,
+  });
+
+  it("should sell a weapon into a matching offer", async () => {
+    const mint = await airdropWeapon(seller.publicKey, 1);
+    const offer = await offerAddressOf(buyer.publicKey, 1);
+    const price = 50_000_000;
+
+    await program.methods
+      .makeOffer(new BN(1), { mint: { mint } }, new BN(price), new BN((await chainTime()) + 600))
+      .accounts({
+        bidder: buyer.publicKey,
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        offer: offer,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([buyer])
+      .rpc();
+
+    const sellerBefore = await lamportsOf(seller.publicKey);
+
+    await program.methods
+      .acceptOffer()
+      .accounts(await acceptOfferAccounts(mint, offer))
+      .signers([seller])
+      .rpc();
+
+    // Same split as `buy`, less the bidder's token account the seller opens.
+    const royalty = 2_500_000;
+    const treasury = 1_250_000 + 10_000;
+    const tokenAccountRent = await provider.connection.getMinimumBalanceForRentExemption(165);
+
+    assert.equal(
+      (await lamportsOf(seller.publicKey)) - sellerBefore,
+      price - royalty - treasury - tokenAccountRent
+    );
+
+    const bidderTokenAccount = await getAccount(provider.connection, await tokenAccountOf(mint, buyer.publicKey));
+
+    assert.equal(Number(bidderTokenAccount.amount), 1);
+    assert.isNull(await provider.connection.getAccountInfo(offer));
+  });
+
+  it("should refuse expired offers and refund them to the bidder", async () => {
+    const mint = await airdropWeapon(seller.publicKey, 1);
+    const offer = await offerAddressOf(buyer.publicKey, 2);
+    const expiresAt = (await chainTime()) + 5;
+
+    await program.methods
+      .makeOffer(new BN(2), { tokenType: { tokenType: 1, minLevel: new BN(0) } }, new BN(20_000_000), new BN(expiresAt))
+      .accounts({
+        bidder: buyer.publicKey,
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        offer: offer,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([buyer])
+      .rpc();
+
+    const closeOffer = () =>
+      program.methods
+        .closeOffer()
+        .accounts({
+          authority: authority.publicKey,
+          bidder: buyer.publicKey,
+          offer: offer,
+        })
+        .signers([authority.payer])
+        .rpc();
+
+    // Only the bidder can withdraw a live offer.
+    await assertProgramError(closeOffer(), 36);
+
+    await waitUntil(expiresAt);
+
+    await assertProgramError(
+      program.methods
+        .acceptOffer()
+        .accounts(await acceptOfferAccounts(mint, offer))
+        .signers([seller])
+        .rpc(),
+      35
+    );
+
+    const escrowed = await lamportsOf(offer);
+    const bidderBefore = await lamportsOf(buyer.publicKey);
+
+    await closeOffer();
+
+    assert.equal((await lamportsOf(buyer.publicKey)) - bidderBefore, escrowed);
+    assert.isNull(await provider.connection.getAccountInfo(offer));
+  });
+
+  it("should only end a rental once it expired", async () => {
+    const mint = await airdropWeapon(seller.publicKey, 1);
+    const rental = await findAddress([Buffer.from("rental"), mint.toBuffer()]);
+    const lenderTokenAccount = await tokenAccountOf(mint, seller.publicKey);
+    const fee = 1_000_000;
+
+    await program.methods
+      .lend(buyer.publicKey, new BN(5), new BN(fee))
+      .accounts({
+        lender: seller.publicKey,
+        mint: mint,
+        lenderTokenAccount: lenderTokenAccount,
+        edition: await editionAddressOf(mint),
+        freezeAuthority: await freezeAuthorityAddress(),
+        rental: rental,
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        metadataAccount: await metadataAddressOf(mint),
+        mintRecord: await mintRecordOf(mint),
+        tokenTypeConfig: await tokenTypeConfigOf(1),
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+      })
+      .signers([seller])
+      .rpc();
+
+    assert.ok((await getAccount(provider.connection, lenderTokenAccount)).isFrozen);
+
+    const lenderBefore = await lamportsOf(seller.publicKey);
+
+    await program.methods
+      .borrow(new BN(fee))
+      .accounts({
+        borrower: buyer.publicKey,
+        lender: seller.publicKey,
+        mint: mint,
+        rental: rental,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([buyer])
+      .rpc();
+
+    assert.equal((await lamportsOf(seller.publicKey)) - lenderBefore, fee);
+
+    const editionAddress = await editionAddressOf(mint);
+    const freezeAuthority = await freezeAuthorityAddress();
+
+    const endRental = () =>
+      program.methods
+        .endRental()
+        .accounts({
+          lender: seller.publicKey,
+          mint: mint,
+          lenderTokenAccount: lenderTokenAccount,
+          edition: editionAddress,
+          freezeAuthority: freezeAuthority,
+          rental: rental,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        })
+        .signers([seller])
+        .rpc();
+
+    const { expiresAt } = await program.account.rental.fetch(rental);
+
+    await assertProgramError(endRental(), 39);
+
+    await waitUntil(expiresAt.toNumber());
+
+    await endRental();
+
+    const tokenAccount = await getAccount(provider.connection, lenderTokenAccount);
+
+    assert.ok(!tokenAccount.isFrozen);
+    assert.isNull(tokenAccount.delegate);
+    assert.isNull(await provider.connection.getAccountInfo(rental));
+  });
+
+  const player = Keypair.generate();
+
+  it("should accrue staking rewards until the weapon is unstaked", async () => {
+    await fund(player.publicKey, 300_000_000);
+
+    const mint = await airdropWeapon(player.publicKey, 1);
+    const stakingConfig = await findAddress([Buffer.from("staking"), upgradeWeaponAccount.publicKey.toBuffer()]);
+    const rewardVault = await findAddress([Buffer.from("rewardVault"), upgradeWeaponAccount.publicKey.toBuffer()]);
+    const rewardMint = await createMint(provider.connection, authority.payer, authority.publicKey, null, 0);
+    const stakeAccount = await findAddress([Buffer.from("stake"), mint.toBuffer()]);
+    const stakerTokenAccount = await tokenAccountOf(mint, player.publicKey);
+    const stakerRewardAccount = await getAssociatedTokenAddress(rewardMint, player.publicKey);
+
+    await program.methods
+      .setStakingConfig([new BN(0), new BN(1_000), new BN(0), new BN(0), new BN(0)])
+      .accounts({
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        stakingConfig: stakingConfig,
+        rewardMint: rewardMint,
+        rewardVault: rewardVault,
+        authority: authority.publicKey,
+        rent: SYSVAR_RENT_PUBKEY,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([authority.payer])
+      .rpc();
+
+    await mintTo(provider.connection, authority.payer, rewardMint, rewardVault, authority.payer, 1_000_000_000);
+
+    await program.methods
+      .stake()
+      .accounts({
+        staker: player.publicKey,
+        mint: mint,
+        stakerTokenAccount: stakerTokenAccount,
+        edition: await editionAddressOf(mint),
+        freezeAuthority: await freezeAuthorityAddress(),
+        stakeAccount: stakeAccount,
+        rental: await findAddress([Buffer.from("rental"), mint.toBuffer()]),
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        metadataAccount: await metadataAddressOf(mint),
+        weaponAccount: await weaponAddressOf(mint),
+        mintRecord: await mintRecordOf(mint),
+        tokenTypeConfig: await tokenTypeConfigOf(1),
+        stakingConfig: stakingConfig,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+      })
+      .signers([player])
+      .rpc();
+
+    assert.ok((await getAccount(provider.connection, stakerTokenAccount)).isFrozen);
+
+    const claimAccounts = {
+      staker: player.publicKey,
+      mint: mint,
+      stakeAccount: stakeAccount,
+      upgradeWeapon: upgradeWeaponAccount.publicKey,
+      weaponAccount: await weaponAddressOf(mint),
+      stakingConfig: stakingConfig,
+      rewardMint: rewardMint,
+      rewardVault: rewardVault,
+      stakerRewardAccount: stakerRewardAccount,
+      systemProgram: SystemProgram.programId,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    const stakedAt = (await program.account.stakeAccount.fetch(stakeAccount)).lastClaimedAt.toNumber();
+
+    await waitUntil(stakedAt + 3);
+
+    await program.methods.claim().accounts(claimAccounts).signers([player]).rpc();
+
+    // A level 0 weapon of type 1 earns the type's rate of 1 000 per second.
+    const claimedAt = (await program.account.stakeAccount.fetch(stakeAccount)).lastClaimedAt.toNumber();
+    const claimed = Number((await getAccount(provider.connection, stakerRewardAccount)).amount);
+
+    assert.ok(claimedAt > stakedAt);
+    assert.equal(claimed, 1_000 * (claimedAt - stakedAt));
+
+    await waitUntil(claimedAt + 1);
+
+    await program.methods
+      .unstake()
+      .accounts({
+        claim: claimAccounts,
+        stakerTokenAccount: stakerTokenAccount,
+        edition: await editionAddressOf(mint),
+        freezeAuthority: await freezeAuthorityAddress(),
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+      })
+      .signers([player])
+      .rpc();
+
+    const tokenAccount = await getAccount(provider.connection, stakerTokenAccount);
+    const unstakedWith = Number((await getAccount(provider.connection, stakerRewardAccount)).amount);
+
+    assert.ok(unstakedWith > claimed);
+    assert.equal((unstakedWith - claimed) % 1_000, 0);
+    assert.ok(!tokenAccount.isFrozen);
+    assert.isNull(tokenAccount.delegate);
+    assert.isNull(await provider.connection.getAccountInfo(stakeAccount));
+  });
+
+  it("should sum the stats of equipped weapons", async () => {
+    const profile = await findAddress([Buffer.from("profile"), player.publicKey.toBuffer()]);
+    const sword = await airdropWeapon(player.publicKey, 1);
+    const staff = await airdropWeapon(player.publicKey, 1);
+
+    await upgradeStats(player, sword, 1, [1, 10, 20, 30, 40, 50]);
+    await upgradeStats(player, staff, 1, [2, 1, 2, 3, 4, 5]);
+
+    const equip = async (mint: PublicKey, slot: number) =>
+      program.methods
+        .equip(slot)
+        .accounts({
+          player: player.publicKey,
+          profile: profile,
+          mint: mint,
+          playerTokenAccount: await tokenAccountOf(mint, player.publicKey),
+          edition: await editionAddressOf(mint),
+          freezeAuthority: await freezeAuthorityAddress(),
+          weaponAccount: await weaponAddressOf(mint),
+          stakeAccount: await findAddress([Buffer.from("stake"), mint.toBuffer()]),
+          rental: await findAddress([Buffer.from("rental"), mint.toBuffer()]),
+          upgradeWeapon: upgradeWeaponAccount.publicKey,
+          metadataAccount: await metadataAddressOf(mint),
+          mintRecord: await mintRecordOf(mint),
+          tokenTypeConfig: await tokenTypeConfigOf(1),
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+        })
+        .signers([player])
+        .rpc();
+
+    // Emitted rather than stored, so read it from a simulation.
+    const characterStats = async (mints: PublicKey[]) => {
+      const { events } = await program.methods
+        .characterStats()
+        .accounts({ profile: profile })
+        .remainingAccounts(
+          await Promise.all(
+            mints.map(async (mint) => ({ pubkey: await weaponAddressOf(mint), isWritable: false, isSigner: false }))
+          )
+        )
+        .simulate();
+
+      const { level, hp, damage, mana, mpRegen, atkSpeed } = events.find(
+        (event) => event.name === "CharacterStatsComputed"
+      ).data;
+
+      return [level, hp, damage, mana, mpRegen, atkSpeed].map((stat) => stat.toNumber());
+    };
+
+    await equip(sword, 0);
+    await equip(staff, 1);
+
+    await assertProgramError(equip(staff, 2), 40);
+
+    assert.deepEqual(await characterStats([sword, staff]), [3, 11, 22, 33, 44, 55]);
+    assert.ok((await getAccount(provider.connection, await tokenAccountOf(sword, player.publicKey))).isFrozen);
+
+    await program.methods
+      .unequip(0)
+      .accounts({
+        player: player.publicKey,
+        profile: profile,
+        mint: sword,
+        playerTokenAccount: await tokenAccountOf(sword, player.publicKey),
+        edition: await editionAddressOf(sword),
+        freezeAuthority: await freezeAuthorityAddress(),
+        weaponAccount: await weaponAddressOf(sword),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+      })
+      .signers([player])
+      .rpc();
+
+    const weapon = await program.account.weapon.fetch(await weaponAddressOf(sword));
+
+    assert.deepEqual(await characterStats([staff]), [2, 1, 2, 3, 4, 5]);
+    assert.ok(weapon.equippedTo.equals(PublicKey.default));
+    assert.ok(!(await getAccount(provider.connection, await tokenAccountOf(sword, player.publicKey))).isFrozen);
+  });
+
+  it("should enforce the upgrade cooldown and daily cap", async () => {
+    const upgradeCounter = await findAddress([
+      Buffer.from("upgradeCounter"),
+      upgradeWeaponAccount.publicKey.toBuffer(),
+      player.publicKey.toBuffer(),
+      Buffer.from([3]),
+    ]);
+    await setTokenTypeConfig("setUpgradeLimits", 3, new BN(3600), 0);
+
+    const mint = await airdropWeapon(player.publicKey, 3);
+
+    await upgradeStats(player, mint, 3, [1, 1, 1, 1, 1, 1]);
+    await assertProgramError(upgradeStats(player, mint, 3, [2, 2, 2, 2, 2, 2]), 44);
+
+    // Allow exactly one more upgrade today, with no cooldown in the way.
+    const { upgrades } = await program.account.upgradeCounter.fetch(upgradeCounter);
+
+    await setTokenTypeConfig("setUpgradeLimits", 3, new BN(0), upgrades + 1);
+
+    await upgradeStats(player, mint, 3, [2, 2, 2, 2, 2, 2]);
+    await assertProgramError(upgradeStats(player, mint, 3, [3, 3, 3, 3, 3, 3]), 45);
+
+    const weapon = await program.account.weapon.fetch(await weaponAddressOf(mint));
+
+    assert.equal(weapon.level.toNumber(), 2);
+
+    await setTokenTypeConfig("setUpgradeLimits", 3, new BN(0), 0);
+  });
+
+  it("should wear weapons down and charge for repairs", async () => {
+    await setTokenTypeConfig("setRepairPrice", 3, new BN(1_000), null);
+
+    const mint = await airdropWeapon(player.publicKey, 3);
+    const weaponAddress = await weaponAddressOf(mint);
+
+    const wear = (amount: number) =>
+      program.methods
+        .wearWeapon(new BN(amount))
+        .accounts({
+          authority: authority.publicKey,
+          weaponAccount: weaponAddress,
+        })
+        .signers([authority.payer])
+        .rpc();
+
+    await wear(40);
+
+    let weapon = await program.account.weapon.fetch(weaponAddress);
+
+    assert.equal(weapon.wear.toNumber(), 40);
+    assert.ok(!weapon.broken);
+
+    await wear(80);
+
+    weapon = await program.account.weapon.fetch(weaponAddress);
+
+    assert.equal(weapon.wear.toNumber(), 100);
+    assert.ok(weapon.broken);
+    await assertProgramError(upgradeStats(player, mint, 3, [1, 1, 1, 1, 1, 1]), 46);
+
+    const holderBefore = await lamportsOf(player.publicKey);
+
+    await program.methods
+      .repair()
+      .accounts({
+        holder: player.publicKey,
+        tokenAccount: await tokenAccountOf(mint, player.publicKey),
+        weaponAccount: weaponAddress,
+        upgradeWeapon: upgradeWeaponAccount.publicKey,
+        mintRecord: await mintRecordOf(mint),
+        tokenTypeConfig: await tokenTypeConfigOf(3),
+        owner: authority.publicKey,
+        // Optional accounts, left out by passing the program id. Type 3 is
+        // repaired in SOL.
+        holderPaymentAccount: programId,
+        treasuryPaymentAccount: programId,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([player])
+      .rpc();
+
+    weapon = await program.account.weapon.fetch(weaponAddress);
+
+    // 100 points of durability at 1 000 lamports each.
+    assert.equal(holderBefore - (await lamportsOf(player.publicKey)), 100_000);
+    assert.equal(weapon.wear.toNumber(), 0);
+    assert.ok(!weapon.broken);
+  });
+
+  // Type 2 levels up through XP: 100 XP for level 1, 250 XP for level 2.
+  const levelTable = [
+    { xp: new BN(100), stats: [10, 10, 10, 10, 10].map((stat) => new BN(stat)) },
+    { xp: new BN(250), stats: [5, 4, 3, 2, 1].map((stat) => new BN(stat)) },
+  ];
+
+  it("should level weapons up from granted XP", async () => {
+    await setTokenTypeConfig("setLevelTable", 2, levelTable, new BN(500), new BN(0));
+
+    const mint = await airdropWeapon(player.publicKey, 2);
+    const weaponAddress = await weaponAddressOf(mint);
+
+    await grantXp(mint, 2, 50);
+
+    let weapon = await program.account.weapon.fetch(weaponAddress);
+
+    assert.equal(weapon.level.toNumber(), 0);
+    assert.equal(weapon.xp.toNumber(), 50);
+
+    // One grant crossing both thresholds applies both level-ups.
+    await grantXp(mint, 2, 200);
+
+    weapon = await program.account.weapon.fetch(weaponAddress);
+
+    assert.equal(weapon.level.toNumber(), 2);
+    assert.deepEqual(
+      [weapon.hp, weapon.damage, weapon.mana, weapon.mpRegen, weapon.atkSpeed].map((stat) => stat.toNumber()),
+      [15, 14, 13, 12, 11]
+    );
+
+    await assertProgramError(grantXp(mint, 2, 501), 49);
+    await assertProgramError(upgradeStats(player, mint, 2, [5, 5, 5, 5, 5, 5]), 50);
+  });
+
+  it("should only roll rarity tiers that have a weight", async () => {
+    // Rare is the only tier with odds, and doubles what level-ups add.
+    await setTokenTypeConfig("setRarityTiers", 2, [
+      { weight: 0, statBonusBps: 0 },
+      { weight: 0, statBonusBps: 0 },
+      { weight: 1, statBonusBps: 10_000 },
+    ]);
+
+    const metaplex = Metaplex.make(provider.connection);
+
+    for (let i = 0; i < 3; i++) {
+      const mint = await airdropWeapon(player.publicKey, 2);
+      const weapon = await program.account.weapon.fetch(await weaponAddressOf(mint));
+      const nft = await metaplex.nfts().findByMint({ mintAddress: mint, loadJsonMetadata: false });
+
+      assert.equal(weapon.rarity, 2);
+      assert.ok(nft.uri.endsWith("/rare"));
+
+      if (i === 0) {
+        await grantXp(mint, 2, 100);
+
+        const levelled = await program.account.weapon.fetch(await weaponAddressOf(mint));
+
+        assert.equal(levelled.level.toNumber(), 1);
+        assert.equal(levelled.hp.toNumber(), 20);
+      }
+    }
+  });
+
+  it("should add and remove exactly a gem's stats when socketing it", async () => {
+    // Type 0 becomes the gem type, and type 3 weapons get two sockets.
+    await setTokenTypeConfig("setGemStats", 0, [5, 4, 3, 2, 1].map((stat) => new BN(stat)));
+    await setTokenTypeConfig("setSockets", 3, 2, new BN(1_000));
+
+    const mint = await airdropWeapon(player.publicKey, 3);
+    const gem = await airdropWeapon(player.publicKey, 0);
+    const weaponAddress = await weaponAddressOf(mint);
+    const gemVault = await findAddress([Buffer.from("gemVault"), gem.toBuffer()]);
+    const holderGemAccount = await tokenAccountOf(gem, player.publicKey);
+
+    await upgradeStats(player, mint, 3, [1, 10, 20, 30, 40, 50]);
+
+    const gemAccounts = {
+      holder: player.publicKey,
+      weaponTokenAccount: await tokenAccountOf(mint, player.publicKey),
+      weaponAccount: weaponAddress,
+      gemMint: gem,
+      holderGemAccount: holderGemAccount,
+      gemVault: gemVault,
+      upgradeWeapon: upgradeWeaponAccount.publicKey,
+      weaponMintRecord: await mintRecordOf(mint),
+      weaponConfig: await tokenTypeConfigOf(3),
+      gemMintRecord: await mintRecordOf(gem),
+      gemConfig: await tokenTypeConfigOf(0),
+      systemProgram: SystemProgram.programId,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    const statsOf = async () => {
+      const weapon = await program.account.weapon.fetch(weaponAddress);
+      return [weapon.hp, weapon.damage, weapon.mana, weapon.mpRegen, weapon.atkSpeed].map((stat) => stat.toNumber());
+    };
+
+    await program.methods
+      .socketGem(1)
+      .accounts({ ...gemAccounts, rent: SYSVAR_RENT_PUBKEY })
+      .signers([player])
+      .rpc();
+
+    let weapon = await program.account.weapon.fetch(weaponAddress);
+
+    assert.deepEqual(await statsOf(), [15, 24, 33, 42, 51]);
+    assert.ok(weapon.gems[1].equals(gem));
+    assert.equal(Number((await getAccount(provider.connection, gemVault)).amount), 1);
+
+    await program.methods
+      .unsocketGem(1)
+      .accounts({
+        ...gemAccounts,
+        owner: authority.publicKey,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      })
+      .signers([player])
+      .rpc();
+
+    weapon = await program.account.weapon.fetch(weaponAddress);
+
+    assert.deepEqual(await statsOf(), [10, 20, 30, 40, 50]);
+    assert.ok(weapon.gems[1].equals(PublicKey.default));
+    assert.deepEqual(weapon.gemBonuses[1].map((bonus) => bonus.toNumber()), [0, 0, 0, 0, 0]);
+    assert.equal(Number((await getAccount(provider.connection, holderGemAccount)).amount), 1);
+    assert.isNull(await provider.connection.getAccountInfo(gemVault));
+  });
 });